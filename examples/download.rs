@@ -14,5 +14,11 @@ async fn main() {
         "1518105".to_string(),
     ];
 
-    download(&pending, &mut puser, path, true).await.unwrap();
+    let options = DownloadOptions {
+        no_video: true,
+        ..Default::default()
+    };
+    download(&pending, &mut puser, path, &options)
+        .await
+        .unwrap();
 }
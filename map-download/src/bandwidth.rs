@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 下载速度上限，单位字节每秒。clone 出来的实例共享同一个额度，
+/// 所以同一个 Bandwidth 限制的是所有使用它的下载加起来的速度
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may pass right now, negative when the callers are ahead of the limit
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Bandwidth {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Take the bytes from the bucket and return how long the caller should wait before
+    /// reading more. A chunk bigger than one second of traffic passes at once, the debt is
+    /// paid by waiting.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until the bytes are allowed through.
+    pub(crate) async fn consume(&self, bytes: u64) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[test]
+fn test_bandwidth_reserve() {
+    let limit = Bandwidth::new(1000);
+    let now = limit.bucket.lock().unwrap().updated;
+
+    // one second of traffic may pass at once
+    assert_eq!(limit.reserve(600, now), Duration::ZERO);
+    assert_eq!(limit.reserve(400, now), Duration::ZERO);
+    assert_eq!(limit.reserve(500, now), Duration::from_millis(500));
+
+    // the clone shares the debt
+    let shared = limit.clone();
+    assert_eq!(shared.reserve(500, now), Duration::from_secs(1));
+
+    // idle for a long time, but never more than one second of tokens
+    let later = now + Duration::from_secs(60);
+    assert_eq!(limit.reserve(1000, later), Duration::ZERO);
+    assert_eq!(limit.reserve(2000, later), Duration::from_secs(2));
+}
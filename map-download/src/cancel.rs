@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// 取消下载的信号，clone 出来的实例共享同一个状态，
+/// 在任意一个上调用 `cancel` 后，使用它的下载都会停止
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop the downloads using this token, calling it again does nothing.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the token is cancelled, return at once if it already is.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// 取消时正在下载的谱面怎么处理，还没开始的谱面都不会再开始
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CancelPolicy {
+    /// Stop the downloads at once and delete their unfinished files
+    #[default]
+    Abort,
+    /// Let the downloads finish, but don't retry the failed ones
+    Finish,
+}
+
+#[tokio::test]
+async fn test_cancel_token() {
+    let token = CancelToken::new();
+    let shared = token.clone();
+    let mut waiter = tokio::spawn(async move { shared.cancelled().await });
+
+    let waiting = tokio::time::timeout(std::time::Duration::from_millis(20), &mut waiter).await;
+    assert!(waiting.is_err());
+    token.cancel();
+    waiter.await.unwrap();
+    assert!(token.is_cancelled());
+    // already cancelled, doesn't wait
+    token.cancelled().await;
+}
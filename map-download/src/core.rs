@@ -1,191 +1,1201 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
+use crate::source::{DownloadRequest, DownloadSource, OfficialSource};
 use crate::user::UserSession;
-use anyhow::{Context, Error, Result};
-use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::header::HeaderMap;
-use reqwest::{Client, Response, StatusCode};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-
-use crate::client;
-use crate::error::OsuMapDownloadError;
-
-/// 封装的下载请求
+use anyhow::{Error, Result};
+use futures_util::{stream, StreamExt};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_RANGE, ETAG, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::{Response, StatusCode};
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{debug, info, instrument, warn, warn_span, Instrument};
+
+use crate::bandwidth::Bandwidth;
+use crate::cancel::{CancelPolicy, CancelToken};
+use crate::client::Client;
+use crate::error::{DownloadError, OsuMapDownloadError};
+use crate::existing::{leading_sid, Existing};
+use crate::naming::{
+    content_disposition_filename, meta_from_filename, BeatmapsetMeta, FileNameTemplate,
+    MetadataProvider,
+};
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::report::{DownloadEntry, DownloadOutcome, DownloadReport};
+use crate::retry::{retry_after, RetryPolicy};
+#[cfg(feature = "verify")]
+use crate::verify::verify_osz;
+
+/// 下载选项
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// 不下载包含视频的文件
+    pub no_video: bool,
+    /// 同时等待响应的下载请求数量上限
+    pub max_requests: usize,
+    /// 同时写入磁盘的文件数量上限
+    pub max_writes: usize,
+    /// 网络波动、服务器错误和限流时的重试策略
+    pub retry: RetryPolicy,
+    /// 下载完成后检查文件是否是包含 .osu 的 zip 压缩包，不是则删除并报错，需要 `verify` feature
+    pub verify: bool,
+    /// 跳过下载目录中已经存在的谱面，文件名需要以 sid 开头
+    pub skip_existing: bool,
+    /// osu! 的 Songs 目录，跳过其中已经解压过的谱面
+    pub songs_dir: Option<PathBuf>,
+    /// 保存的文件名模板，默认为 {sid}.osz
+    pub file_name: FileNameTemplate,
+    /// 文件名模板需要 Content-Disposition 中没有的谱面信息时，从这里查询
+    pub metadata: Option<Arc<dyn MetadataProvider>>,
+    /// 接收下载进度，默认不输出
+    pub progress: Arc<dyn ProgressObserver>,
+    /// 下载使用的 HTTP 客户端，默认使用下载源的客户端，比如 UserSession 的
+    pub client: Option<Client>,
+    /// 所有下载加起来的速度上限，默认不限速
+    pub bandwidth: Option<Bandwidth>,
+    /// 单个谱面的速度上限，单位字节每秒，默认不限速
+    pub bandwidth_per_download: Option<u64>,
+    /// 取消后不再开始新的谱面，报告中记为 NotStarted
+    pub cancel: Option<CancelToken>,
+    /// 取消时正在下载的谱面是中止还是下载完
+    pub cancel_policy: CancelPolicy,
+}
+
+impl fmt::Debug for DownloadOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadOptions")
+            .field("no_video", &self.no_video)
+            .field("max_requests", &self.max_requests)
+            .field("max_writes", &self.max_writes)
+            .field("retry", &self.retry)
+            .field("verify", &self.verify)
+            .field("skip_existing", &self.skip_existing)
+            .field("songs_dir", &self.songs_dir)
+            .field("file_name", &self.file_name)
+            .field("client", &self.client)
+            .field(
+                "bandwidth",
+                &self.bandwidth.as_ref().map(Bandwidth::bytes_per_sec),
+            )
+            .field("bandwidth_per_download", &self.bandwidth_per_download)
+            .field("cancel", &self.cancel)
+            .field("cancel_policy", &self.cancel_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            no_video: false,
+            max_requests: 4,
+            max_writes: 4,
+            retry: RetryPolicy::default(),
+            verify: true,
+            skip_existing: false,
+            songs_dir: None,
+            file_name: FileNameTemplate::default(),
+            metadata: None,
+            progress: Arc::new(NoProgress),
+            client: None,
+            bandwidth: None,
+            bandwidth_per_download: None,
+            cancel: None,
+            cancel_policy: CancelPolicy::default(),
+        }
+    }
+}
+
+impl DownloadOptions {
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Wait until the download is cancelled, never returns without a token.
+    async fn cancelled(&self) {
+        match &self.cancel {
+            Some(token) => token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// 封装的下载请求，返回每个 sid 的结果，顺序和传入的 sid 一致，重复的 sid 只下载一次
 async fn try_download(
     sid: &[String],
-    user: &UserSession,
+    source: &dyn DownloadSource,
     path: &Path,
-    no_video: bool,
-) -> Result<(), OsuMapDownloadError> {
-    // build a pair of the url and related headers
-    let requisite: Vec<(String, String, HeaderMap)> = sid
-        .iter()
-        .map(|s| {
-            (
-                s.clone(),
-                format!(
-                    "https://osu.ppy.sh/beatmapsets/{s}/download?noVideo={}",
-                    if no_video { "" } else { "1" }
-                ),
-                user.new_header(&format!("https://osu.ppy.sh/beatmapsets/{s}")),
-            )
-        })
-        .collect();
+    options: &DownloadOptions,
+) -> Vec<DownloadEntry> {
+    let requests = Semaphore::new(options.max_requests.max(1));
+    let batch = Batch {
+        writers: Semaphore::new(options.max_writes.max(1)),
+        names: Mutex::default(),
+    };
+    let batch_started = Instant::now();
+    let existing = Existing::scan(path, options).await;
+    let client = options
+        .client
+        .as_ref()
+        .or_else(|| source.client())
+        .cloned()
+        .unwrap_or_default();
+    // the same sid twice would write the same part file at the same time
+    let mut seen = HashSet::new();
+    let sid: Vec<&String> = sid.iter().filter(|s| seen.insert(*s)).collect();
+    let total = sid.len();
 
-    // send the download request concurrently
-    let mut tasks = Vec::with_capacity(requisite.len());
-    for (sid, url, headers) in requisite {
-        let t = tokio::spawn(async move {
-            (
-                sid.clone(),
-                client::get(&url, headers.clone())
-                    .await
-                    .map_err(|_| OsuMapDownloadError::DownloadRequestError),
-            )
-        });
-        tasks.push(t);
-    }
-
-    // write the response to disk concurrently
-    let mut write_task = Vec::with_capacity(tasks.capacity());
-    for handle in tasks {
-        let path = path.to_owned();
-        let (sid, response) = handle.await.expect("执行下载任务时发生了意料之外的错误");
-        match response {
-            Ok(resp) => {
-                if resp.status() == StatusCode::NOT_FOUND {
-                    eprintln!("{}", OsuMapDownloadError::NotFoundMapError);
-                    continue;
+    // the sid list is consumed lazily, a sid only holds a connection while it is waiting
+    // for the response or writing the file, so no more than `max_requests + max_writes`
+    // sids are running at the same time
+    let mut tasks = stream::iter(sid.into_iter().enumerate())
+        .map(|(index, sid)| {
+            let (requests, batch, existing, client) = (&requests, &batch, &existing, &client);
+            // the span is at warn level so that the failures still carry the sid by default
+            let span = warn_span!("download", sid = %sid, source = source.name());
+            async move {
+                let started = Instant::now();
+                if let Some(found) = existing.find(sid) {
+                    debug!(path = %found.display(), "already downloaded, skipped");
+                    let entry = DownloadEntry {
+                        sid: sid.clone(),
+                        duration: started.elapsed(),
+                        outcome: DownloadOutcome::Skipped { path: found },
+                    };
+                    return (index, entry);
                 }
 
-                if resp.status() == StatusCode::OK {
-                    write_task.push(tokio::spawn(async move {
-                        write_file(resp, path.to_path_buf(), sid).await
-                    }));
+                // every sid is retried on its own, the slots are released while waiting
+                let mut attempt = 1;
+                let res = loop {
+                    // a sid that never got a request slot has not started
+                    let request_permit = tokio::select! {
+                        biased;
+                        _ = options.cancelled(), if attempt == 1 => {
+                            debug!("cancelled before starting");
+                            let entry = DownloadEntry {
+                                sid: sid.clone(),
+                                duration: started.elapsed(),
+                                outcome: DownloadOutcome::NotStarted,
+                            };
+                            return (index, entry);
+                        }
+                        _ = options.cancelled() => break Err(OsuMapDownloadError::Cancelled.into()),
+                        permit = requests.acquire() => match permit {
+                            Ok(permit) => permit,
+                            Err(e) => {
+                                break Err(DownloadError::new(OsuMapDownloadError::Unknown).with_source(e))
+                            }
+                        },
+                    };
+                    let fetching =
+                        fetch(sid, source, client, path, options, request_permit, batch);
+                    let res = match options.cancel_policy {
+                        CancelPolicy::Finish => fetching.await,
+                        CancelPolicy::Abort => tokio::select! {
+                            res = fetching => res,
+                            _ = options.cancelled() => {
+                                // the file is closed when `fetching` is dropped
+                                remove_part(&part_path(path, sid)).await;
+                                Err(OsuMapDownloadError::Cancelled.into())
+                            }
+                        },
+                    };
+                    match &res {
+                        Ok(_) => source.on_success(sid),
+                        Err(e) => source.on_error(sid, e.kind()),
+                    }
+                    match res
+                        .as_ref()
+                        .err()
+                        .and_then(|e| options.retry.delay(e.kind(), attempt, batch_started))
+                    {
+                        Some(delay) => {
+                            if let Err(e) = &res {
+                                let delay_ms = delay.as_millis() as u64;
+                                warn!(attempt, delay_ms, error = %e, "download failed, retrying");
+                            }
+                            // no more retries after cancelling, keep the last error
+                            tokio::select! {
+                                _ = tokio::time::sleep(delay) => (),
+                                _ = options.cancelled() => break res,
+                            }
+                            attempt += 1;
+                        }
+                        None => break res,
+                    }
+                };
+                let res = res.map_err(|e| e.with_sid(sid.as_str()));
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                match &res {
+                    Ok((path, bytes)) => info!(bytes, elapsed_ms, path = %path.display(), "saved"),
+                    Err(e) => {
+                        warn!(attempt, elapsed_ms, error = %e, "download failed");
+                        options.progress.failed(sid, e);
+                    }
                 }
+                (index, new_entry(sid.clone(), started, res))
             }
-            Err(e) => {
-                eprintln!("{e}");
-            }
-        }
+            .instrument(span)
+        })
+        .buffer_unordered(options.max_requests.max(1) + options.max_writes.max(1));
+
+    let mut entries: Vec<Option<DownloadEntry>> = vec![None; total];
+    while let Some((index, entry)) = tasks.next().await {
+        entries[index] = Some(entry);
     }
 
-    Ok(())
+    entries.into_iter().flatten().collect()
+}
+
+/// State shared by the downloads of one call to `try_download`
+struct Batch {
+    writers: Semaphore,
+    /// The files saved in this batch and their sid
+    names: Mutex<HashMap<PathBuf, String>>,
 }
 
-/// 下载方法,使用 UserSession 信息下载
-/// 如果短时间大量下载,尽可能使用不同的user下载
+/// 单个 sid 的完整下载流程：发送请求，然后把响应写入文件
+async fn fetch(
+    sid: &str,
+    source: &dyn DownloadSource,
+    client: &Client,
+    path: &Path,
+    options: &DownloadOptions,
+    request_permit: SemaphorePermit<'_>,
+    batch: &Batch,
+) -> Result<(PathBuf, u64), DownloadError> {
+    let request = source.request(sid, options.no_video).await.map_err(|e| {
+        DownloadError::new(OsuMapDownloadError::DownloadRequestError).with_source(e)
+    })?;
+    let client = match (&options.client, &request.client) {
+        (None, Some(own)) => own,
+        _ => client,
+    }
+    .clone();
+    // continue from the last .part file, only if the server can tell that the beatmapset
+    // hasn't changed since, otherwise start over
+    let part = part_path(path, sid);
+    let validator = saved_validator(&part).await;
+    let offset = match validator {
+        Some(_) => part_len(&part).await,
+        None => 0,
+    };
+    let resp = client
+        .get(
+            &request.url,
+            with_range(&request.headers, offset, validator.as_ref()),
+        )
+        .await?;
+    let status = resp.status();
+    let kind = match status {
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => None,
+        StatusCode::NOT_FOUND => Some(OsuMapDownloadError::NotFoundMapError),
+        StatusCode::TOO_MANY_REQUESTS => Some(OsuMapDownloadError::TooManyRequestsError {
+            retry_after: retry_after(resp.headers()),
+        }),
+        status if status.is_server_error() => Some(OsuMapDownloadError::ServerError {
+            status: status.as_u16(),
+        }),
+        _ => Some(OsuMapDownloadError::DownloadRequestError),
+    };
+    if let Some(kind) = kind {
+        return Err(DownloadError::new(kind)
+            .with_url(&request.url)
+            .with_status(status.as_u16()));
+    }
+
+    // hand over to the write stage, keep the request slot until a writer is free so the
+    // response doesn't wait in an unbounded queue
+    let _write_permit = batch
+        .writers
+        .acquire()
+        .await
+        .map_err(|e| DownloadError::new(OsuMapDownloadError::Unknown).with_source(e))?;
+    drop(request_permit);
+
+    write_file(
+        resp,
+        request,
+        &client,
+        path.to_owned(),
+        sid.to_string(),
+        options,
+        &batch.names,
+    )
+    .await
+}
+
+fn new_entry(
+    sid: String,
+    started: Instant,
+    res: Result<(PathBuf, u64), DownloadError>,
+) -> DownloadEntry {
+    DownloadEntry {
+        sid,
+        duration: started.elapsed(),
+        outcome: match res {
+            Ok((path, bytes)) => DownloadOutcome::Saved { path, bytes },
+            Err(e) => DownloadOutcome::Failed(e),
+        },
+    }
+}
+
+/// 下载方法,使用 UserSession 信息从官网下载
+/// 如果短时间大量下载,尽可能使用不同的user下载 (见 SessionPool),或者用 set_rate_limit 限制请求频率
 /// 使用Tokio以及reqwest依赖,确保版本匹配
 pub async fn download(
     sid: &[String],
     user: &mut UserSession,
     download_file_path: &Path,
-    no_video: bool,
-) -> Result<()> {
-    let res = try_download(sid, user, download_file_path, no_video).await;
+    options: &DownloadOptions,
+) -> Result<DownloadReport> {
+    let guard = SessionGuard {
+        source: Some(OfficialSource::new(std::mem::take(user))),
+        user,
+    };
+    let source = guard.source.as_ref().expect("taken only on drop");
+    download_from(sid, source, download_file_path, options).await
+}
+
+/// Give the session back to the caller of [`download`] when it finishes, also when the
+/// future is dropped before that
+struct SessionGuard<'a> {
+    source: Option<OfficialSource>,
+    user: &'a mut UserSession,
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            *self.user = source.into_session();
+        }
+    }
+}
+
+/// 从指定的下载源下载,镜像站不需要 osu 账号
+/// 单个谱面的失败记录在返回的 DownloadReport 里，刷新下载源失败时，被拒绝的谱面仍然是失败，
+/// 刷新的错误作为它们的 `source()`
+pub async fn download_from(
+    sid: &[String],
+    source: &dyn DownloadSource,
+    download_file_path: &Path,
+    options: &DownloadOptions,
+) -> Result<DownloadReport> {
+    let mut entries = try_download(sid, source, download_file_path, options).await;
 
-    // match response. If return is Ok, we return ok.
-    // If return is download request error, we refresh cookie and retry download
-    // If return is other error, return error String.
-    match res {
-        Ok(_) => return Ok(()),
-        Err(e) if e == OsuMapDownloadError::DownloadRequestError => (),
-        Err(e) => anyhow::bail!("{}", e),
+    // If some request was rejected, the session may be expired, refresh the source and
+    // retry those sids. Sources without anything to refresh keep the failures.
+    let rejected: Vec<String> = entries
+        .iter()
+        .filter(|e| {
+            e.error().map(DownloadError::kind) == Some(&OsuMapDownloadError::DownloadRequestError)
+        })
+        .map(|e| e.sid.clone())
+        .collect();
+    if rejected.is_empty() || options.is_cancelled() {
+        return Ok(DownloadReport { entries });
     }
 
     // session 可能超时失效 ,进行刷新
-    println!("Fail to download, try refreshing..");
-    user.refresh().await?;
+    info!(
+        rejected = rejected.len(),
+        source = source.name(),
+        "requests rejected, refreshing the source"
+    );
+    match source.refresh().await {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!(source = source.name(), "nothing to refresh, giving up");
+            return Ok(DownloadReport { entries });
+        }
+        Err(e) => {
+            warn!(source = source.name(), error = %e, "refreshing the source failed");
+            let refresh_error = e.downcast::<DownloadError>().unwrap_or_else(|e| {
+                DownloadError::new(OsuMapDownloadError::Unknown).with_source(e)
+            });
+            for entry in entries.iter_mut().filter(|e| rejected.contains(&e.sid)) {
+                if let DownloadOutcome::Failed(error) = &mut entry.outcome {
+                    *error = error.clone().with_source(refresh_error.clone());
+                }
+            }
+            return Ok(DownloadReport { entries });
+        }
+    }
+
+    for retried in try_download(&rejected, source, download_file_path, options).await {
+        if let Some(entry) = entries.iter_mut().find(|e| e.sid == retried.sid) {
+            *entry = retried;
+        }
+    }
+
+    Ok(DownloadReport { entries })
+}
 
-    try_download(sid, user, download_file_path, no_video).await?;
+/// 单个谱面下载中断后最多续传的次数
+const MAX_RESUME: usize = 3;
 
-    // 登录失败抛出错误
-    Err(OsuMapDownloadError::LoginFailError.into())
+/// Path of the unfinished file: {prefix}/{sid}.osz.part
+fn part_path(prefix: &Path, sid: &str) -> PathBuf {
+    prefix.join(format!("{sid}.osz.part"))
+}
+
+/// Size of the unfinished file, 0 if it doesn't exist.
+async fn part_len(part: &Path) -> u64 {
+    tokio::fs::metadata(part).await.map_or(0, |m| m.len())
+}
+
+/// The ETag or Last-Modified of the response the part file came from is kept next to it.
+fn validator_path(part: &Path) -> PathBuf {
+    part.with_extension("part.validator")
+}
+
+/// A strong ETag, or Last-Modified if there is none, usable in `If-Range`.
+fn response_validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers
+        .get(ETAG)
+        .filter(|v| !v.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned()
+}
+
+async fn saved_validator(part: &Path) -> Option<HeaderValue> {
+    let saved = tokio::fs::read_to_string(validator_path(part)).await.ok()?;
+    HeaderValue::from_str(saved.trim()).ok()
+}
+
+/// Remember the validator of a fresh response, a part file without one is never resumed.
+async fn save_validator(part: &Path, validator: Option<&HeaderValue>) {
+    let path = validator_path(part);
+    match validator {
+        Some(validator) => {
+            let _ = tokio::fs::write(path, validator.as_bytes()).await;
+        }
+        None => {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
 }
 
-/// Write the response to file with stream. Require reqwest::Response, path to write file, and the
-/// unique set id. The file will be write to: {write_to}/sid.zip.
+async fn remove_part(part: &Path) {
+    let _ = tokio::fs::remove_file(part).await;
+    let _ = tokio::fs::remove_file(validator_path(part)).await;
+}
+
+/// Copy the headers and ask the server to start from `offset`. With a validator the server
+/// sends the whole file instead if it has changed.
+fn with_range(headers: &HeaderMap, offset: u64, validator: Option<&HeaderValue>) -> HeaderMap {
+    let mut headers = headers.clone();
+    if offset > 0 {
+        headers.insert(RANGE, format!("bytes={offset}-").parse().unwrap());
+        if let Some(validator) = validator {
+            headers.insert(IF_RANGE, validator.clone());
+        }
+    }
+    headers
+}
+
+/// Parse `Content-Range: bytes 100-199/200` into the start offset and the complete size.
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+/// Find the beatmapset info for the file name template, from the `Content-Disposition` file
+/// name first, and the metadata provider if that's not enough.
+async fn lookup_meta(
+    sid: &str,
+    filename: Option<&str>,
+    options: &DownloadOptions,
+) -> Option<BeatmapsetMeta> {
+    if !options.file_name.needs_meta() {
+        return None;
+    }
+    let from_header = filename.and_then(|name| meta_from_filename(sid, name));
+    match &options.metadata {
+        Some(provider) if from_header.is_none() || options.file_name.needs_creator() => {
+            provider.beatmapset(sid).await.ok().or(from_header)
+        }
+        _ => from_header,
+    }
+}
+
+/// Write the response to file with stream. Require reqwest::Response, the request to resume
+/// from, path to write file, and the unique set id. Data is written into {write_to}/sid.osz.part
+/// first, and renamed to the name from `options.file_name` after the size matches. When the
+/// connection drops and the server supports `Range`, the download continues from where it
+/// stopped, `If-Range` makes the server send the whole file again if it has changed.
+/// If `options.verify` is set, the part file must be a valid .osz before being renamed,
+/// otherwise it is deleted. Return the saved path and its size.
 async fn write_file(
-    resp: Response,
-    mut prefix: PathBuf,
+    mut resp: Response,
+    request: DownloadRequest,
+    client: &Client,
+    prefix: PathBuf,
     sid: String,
-) -> Result<(), OsuMapDownloadError> {
-    let total_size = resp
-        .content_length()
-        .ok_or(OsuMapDownloadError::UnknownSizeError)?;
-
-    prefix.push(format!("{sid}.osz"));
-    let path = prefix.to_str().expect("非法路径名").to_string();
-    let bar = ProgressBar::new(total_size);
-    bar.set_style(ProgressStyle::default_bar()
-        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-        .progress_chars("#>-"));
-    bar.set_message(format!("正在下载谱面 {sid}"));
-    let mut file =
-        File::create(prefix)
+    options: &DownloadOptions,
+    names: &Mutex<HashMap<PathBuf, String>>,
+) -> Result<(PathBuf, u64), DownloadError> {
+    let progress = options.progress.as_ref();
+    let part = part_path(&prefix, &sid);
+    // the cap of this download stays the same after resuming
+    let own = options.bandwidth_per_download.map(Bandwidth::new);
+    let limits: Vec<&Bandwidth> = options.bandwidth.iter().chain(own.as_ref()).collect();
+    let filename = resp
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_disposition_filename);
+    let failed = |kind: OsuMapDownloadError| DownloadError::new(kind).with_url(&request.url);
+
+    // the validator of the response the part file came from
+    let mut validator = None;
+    let mut resumed = 0;
+    let total_size = loop {
+        // the part file is complete or broken, start over
+        if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            remove_part(&part).await;
+            resp = client
+                .get(&request.url, request.headers.clone())
+                .await
+                .map_err(|e| failed(OsuMapDownloadError::DownloadPartError).with_source(e))?;
+        }
+
+        let (offset, total_size) = match resp.status() {
+            StatusCode::OK => (
+                0,
+                resp.content_length()
+                    .ok_or_else(|| failed(OsuMapDownloadError::UnknownSizeError))?,
+            ),
+            StatusCode::PARTIAL_CONTENT => resp
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range)
+                .ok_or_else(|| failed(OsuMapDownloadError::UnknownSizeError))?,
+            status => {
+                return Err(
+                    failed(OsuMapDownloadError::DownloadPartError).with_status(status.as_u16())
+                )
+            }
+        };
+        let fresh = resp.status() == StatusCode::OK;
+        if fresh {
+            validator = response_validator(resp.headers());
+        }
+        let resumable = resp.status() == StatusCode::PARTIAL_CONTENT
+            || resp
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_some_and(|v| v.as_bytes() == b"bytes");
+
+        progress.started(&sid, total_size, offset);
+        if fresh {
+            save_validator(&part, validator.as_ref()).await;
+        } else if validator.is_none() {
+            validator = saved_validator(&part).await;
+        }
+        let timeout = client.read_timeout();
+        let written = write_part(resp, &part, &sid, offset, timeout, progress, &limits).await?;
+        if written == total_size {
+            break total_size;
+        }
+
+        if !resumable || resumed >= MAX_RESUME {
+            return Err(failed(OsuMapDownloadError::DownloadPartError));
+        }
+        resumed += 1;
+        debug!(written, total_size, resumed, "connection dropped, resuming");
+        resp = client
+            .get(
+                &request.url,
+                with_range(&request.headers, written, validator.as_ref()),
+            )
             .await
-            .map_err(|e| OsuMapDownloadError::TargetFileCreationError {
-                path: path.clone(),
-                error: e.to_string(),
-            })?;
-    let mut downloaded = 0;
-    let mut resp_stream = resp.bytes_stream();
+            .map_err(|e| failed(OsuMapDownloadError::DownloadPartError).with_source(e))?;
+    };
 
-    while let Some(chunk) = resp_stream.next().await {
-        let chunk = chunk.map_err(|_| OsuMapDownloadError::DownloadPartError)?;
-        file.write_all(&chunk)
+    #[cfg(feature = "verify")]
+    if options.verify {
+        let checked = part.clone();
+        let verified = tokio::task::spawn_blocking(move || verify_osz(&checked))
             .await
-            .with_context(|| "下载文件时出现错误")
-            .map_err(|e| OsuMapDownloadError::TargetFileWriteError {
-                path: path.clone(),
-                error: e.to_string(),
-            })?;
-        let new = std::cmp::min(downloaded + (chunk.len() as u64), total_size);
-        downloaded = new;
-        bar.set_position(new);
+            .map_err(|e| DownloadError::new(OsuMapDownloadError::Unknown).with_source(e))?;
+        if let Err(e) = verified {
+            remove_part(&part).await;
+            return Err(e.into());
+        }
+    }
+
+    let meta = lookup_meta(&sid, filename.as_deref(), options).await;
+    let name = options
+        .file_name
+        .render(&sid, meta.as_ref(), options.no_video);
+    let target = free_target(&prefix, &name, &sid, names);
+    tokio::fs::rename(&part, &target).await.map_err(|e| {
+        DownloadError::new(OsuMapDownloadError::TargetFileWriteError {
+            path: target.display().to_string(),
+        })
+        .with_source(e)
+    })?;
+    let _ = tokio::fs::remove_file(validator_path(&part)).await;
+
+    progress.finished(&sid, &target);
+    Ok((target, total_size))
+}
+
+/// Where to save the file named `name`. A file of the same sid is replaced, but another file
+/// with that name is kept and this one gets a `-{sid}` suffix instead. The chosen path is
+/// reserved in `names` before it is renamed to, so two sids of the same batch can't both take
+/// a name that doesn't exist yet.
+fn free_target(
+    prefix: &Path,
+    name: &str,
+    sid: &str,
+    names: &Mutex<HashMap<PathBuf, String>>,
+) -> PathBuf {
+    let mut names = names.lock().unwrap();
+    let target = prefix.join(name);
+    let free = match names.get(&target) {
+        Some(owner) => owner == sid,
+        None => !target.exists() || leading_sid(name) == Some(sid),
+    };
+    let target = if free {
+        target
+    } else {
+        let stem = name.strip_suffix(".osz").unwrap_or(name);
+        prefix.join(format!("{stem}-{sid}.osz"))
+    };
+    names.insert(target.clone(), sid.to_string());
+    target
+}
+
+/// Stream the response body into the part file from `offset`, return the size of the part
+/// file when the stream ends or breaks. Every chunk waits for all the bandwidth limits before
+/// the next one is read.
+async fn write_part(
+    resp: Response,
+    part: &Path,
+    sid: &str,
+    offset: u64,
+    read_timeout: Option<Duration>,
+    progress: &dyn ProgressObserver,
+    limits: &[&Bandwidth],
+) -> Result<u64, DownloadError> {
+    let path = part.display().to_string();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(part)
+        .await
+        .map_err(|e| {
+            DownloadError::new(OsuMapDownloadError::TargetFileCreationError { path: path.clone() })
+                .with_source(e)
+        })?;
+    let write_error = |e: std::io::Error| {
+        DownloadError::new(OsuMapDownloadError::TargetFileWriteError {
+            path: path.to_string(),
+        })
+        .with_source(e)
+    };
+    file.set_len(offset).await.map_err(write_error)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(write_error)?;
+
+    let mut downloaded = offset;
+    let mut resp_stream = resp.bytes_stream();
+    loop {
+        let next = resp_stream.next();
+        let chunk = match read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, next).await.ok().flatten(),
+            None => next.await,
+        };
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            None => break,
+            // connection dropped or stalled, keep what we have got
+            Some(Err(_)) => break,
+        };
+        file.write_all(&chunk).await.map_err(write_error)?;
+        downloaded += chunk.len() as u64;
+        progress.advanced(sid, chunk.len() as u64);
+        for limit in limits {
+            limit.consume(chunk.len() as u64).await;
+        }
     }
+    file.flush().await.map_err(write_error)?;
 
-    bar.finish_with_message(format!("谱面下载完成，保存到: {path}"));
-    Ok(())
+    Ok(downloaded)
 }
 
 /// 通过访问 https://osu.ppy.sh/b/{bid} 接口跳转到标准链接来获取sid,并更新cookie
 /// 暂时没有使用
-#[warn(dead_code)]
-pub async fn bid_to_sid(bid: u32, user:&mut UserSession) -> Result<u32,Error> {
-    let header = user.new_header("");
-    let rep = client::get(&format!("https://osu.ppy.sh/b/{bid}"), header).await?;
+#[allow(dead_code)]
+#[instrument(skip(user))]
+pub async fn bid_to_sid(bid: u32, user: &mut UserSession) -> Result<u32, Error> {
+    let header = user.new_header("")?;
+    let url = format!("{}/b/{bid}", user.base_url());
+    let rep = user.client().get(&url, header).await?;
 
     user.update(rep.headers());
 
-    let nurl = rep.url().to_string();
-    let sid = nurl
-        .split_at(nurl.find("#").unwrap()).0
-        .split_at(31).1
-        .to_string()
-        .parse::<u32>().unwrap();
-    Ok(sid)
+    // https://osu.ppy.sh/beatmapsets/1748483#osu/3594765
+    let mut segments = rep.url().path_segments().into_iter().flatten();
+    segments
+        .find(|s| *s == "beatmapsets")
+        .and_then(|_| segments.next())
+        .and_then(|sid| sid.parse().ok())
+        .ok_or_else(|| {
+            DownloadError::new(OsuMapDownloadError::NotFoundMapError)
+                .with_url(&url)
+                .into()
+        })
 }
+
 #[tokio::test]
-async fn test_bid_to_sid(){
-    let client = Client::new();
+async fn test_bid_to_sid() {
+    use crate::testing::MockOsu;
 
-    let rep = client
-        .get("https://osu.ppy.sh/b/3594765")
-        .send()
+    let osu = MockOsu::start()
+        .await
+        .with_account("foo", "bar")
+        .with_beatmapset(1748483, &[3594765, 3594766]);
+    let mut user = UserSession::new_with_client(osu.client(), "foo", "bar")
         .await
         .unwrap();
 
-    let nurl = rep
-        .url()
-        .to_string();
-    let sid = nurl
-        .split_at(nurl.find("#").unwrap()).0
-        .split_at(31).1
-        .to_string()
-        .parse::<u32>().unwrap();
-    // https://osu.ppy.sh/beatmapsets/1748483#osu/3594765
-    assert_eq!(1748483, sid);
-}
\ No newline at end of file
+    assert_eq!(bid_to_sid(3594765, &mut user).await.unwrap(), 1748483);
+    assert_eq!(bid_to_sid(3594766, &mut user).await.unwrap(), 1748483);
+    assert!(bid_to_sid(1, &mut user).await.is_err());
+}
+
+#[tokio::test]
+async fn test_download_offline() {
+    use crate::testing::{Failure, MockOsu, TempDir};
+    use futures_util::FutureExt;
+
+    let osu = MockOsu::start()
+        .await
+        .with_account("foo", "bar")
+        .with_beatmapset(1, &[11, 12])
+        .with_beatmapset(2, &[21]);
+    let dir = TempDir::new("download-offline");
+
+    let mut user = UserSession::new_with_client(osu.client(), "foo", "bar")
+        .await
+        .unwrap();
+    assert_eq!(osu.logins(), 1);
+
+    // the first download breaks halfway and is resumed, the unknown sid is reported
+    osu.fail_next(1, Failure::Truncated);
+    let options = DownloadOptions {
+        retry: RetryPolicy::none(),
+        ..Default::default()
+    };
+    let sid = vec!["1".to_string(), "3".to_string()];
+    let report = download(&sid, &mut user, &dir, &options).await.unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("1.osz")).unwrap(),
+        osu.archive(1).unwrap()
+    );
+    assert_eq!(
+        report.entries[1].error().map(DownloadError::kind),
+        Some(&OsuMapDownloadError::NotFoundMapError)
+    );
+
+    // the cookie expires during the download, log in again and retry. The leftover part
+    // file is longer than the archive, the server answers 416 and it starts over. The
+    // repeated sid is only downloaded once
+    std::fs::write(
+        dir.join("2.osz.part"),
+        vec![0; osu.archive(2).unwrap().len() + 10],
+    )
+    .unwrap();
+    std::fs::write(dir.join("2.osz.part.validator"), osu.etag(2).unwrap()).unwrap();
+    osu.fail_next(2, Failure::ExpireSessions);
+    let sid = vec!["2".to_string(), "2".to_string()];
+    let report = download(&sid, &mut user, &dir, &options).await.unwrap();
+    assert!(report.is_success());
+    assert_eq!(report.entries.len(), 1);
+    assert_eq!(osu.logins(), 2);
+    assert_eq!(
+        std::fs::read(dir.join("2.osz")).unwrap(),
+        osu.archive(2).unwrap()
+    );
+    assert!(!dir.join("2.osz.part.validator").exists());
+
+    // a part file of an older archive, or one that can't be checked, is not resumed
+    for validator in [Some("\"old\""), None] {
+        std::fs::write(dir.join("2.osz.part"), [0; 10]).unwrap();
+        if let Some(validator) = validator {
+            std::fs::write(dir.join("2.osz.part.validator"), validator).unwrap();
+        }
+        let report = download(&["2".to_string()], &mut user, &dir, &options)
+            .await
+            .unwrap();
+        assert!(report.is_success());
+        assert_eq!(
+            std::fs::read(dir.join("2.osz")).unwrap(),
+            osu.archive(2).unwrap()
+        );
+    }
+
+    // the session is given back even if the download is dropped halfway
+    let dropped = download(&["3".to_string()], &mut user, &dir, &options).now_or_never();
+    assert!(dropped.is_none());
+    assert_eq!(user.username(), "foo");
+
+    // a session restored without the password can't log in again after the cookie expires
+    // at sid 2, the sid saved before is kept in the report and sid 2 carries the login error
+    let mut user = UserSession::restore("foo", &user.to_recoverable())
+        .unwrap()
+        .with_client(osu.client());
+    let sid = vec!["1".to_string(), "2".to_string()];
+    let options = DownloadOptions {
+        max_requests: 1,
+        ..options
+    };
+    osu.fail_next(2, Failure::ExpireSessions);
+    let report = download(&sid, &mut user, &dir, &options).await.unwrap();
+    assert!(matches!(
+        report.entries[0].outcome,
+        DownloadOutcome::Saved { .. }
+    ));
+    let err = report.entries[1].error().unwrap();
+    assert_eq!(err.kind(), &OsuMapDownloadError::DownloadRequestError);
+    let source = std::error::Error::source(err)
+        .and_then(|e| e.downcast_ref::<DownloadError>())
+        .map(DownloadError::kind);
+    assert_eq!(source, Some(&OsuMapDownloadError::IncorrectPasswordError));
+}
+
+#[test]
+fn test_parse_content_range() {
+    assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, 200)));
+    assert_eq!(parse_content_range("bytes */200"), None);
+    assert_eq!(parse_content_range("items 0-1/2"), None);
+}
+
+#[test]
+fn test_free_target() {
+    use crate::testing::TempDir;
+
+    let dir = TempDir::new("free-target");
+    let names = Mutex::default();
+    assert_eq!(free_target(&dir, "1.osz", "1", &names), dir.join("1.osz"));
+    std::fs::write(dir.join("1.osz"), "").unwrap();
+    std::fs::write(dir.join("Art - Title.osz"), "").unwrap();
+    // downloaded again
+    assert_eq!(free_target(&dir, "1.osz", "1", &names), dir.join("1.osz"));
+    // a file of the user, or of an earlier download
+    assert_eq!(
+        free_target(&dir, "Art - Title.osz", "2", &names),
+        dir.join("Art - Title-2.osz")
+    );
+    // two sids of the same batch with the same name, before either of them is renamed
+    assert_eq!(
+        free_target(&dir, "New.osz", "3", &names),
+        dir.join("New.osz")
+    );
+    assert_eq!(
+        free_target(&dir, "New.osz", "4", &names),
+        dir.join("New-4.osz")
+    );
+    // a retry keeps its name
+    assert_eq!(
+        free_target(&dir, "New.osz", "3", &names),
+        dir.join("New.osz")
+    );
+}
+
+#[tokio::test]
+async fn test_write_file_resume() {
+    use crate::testing::{Reply, StandIn, TempDir};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let content: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let served = content.clone();
+    let count = AtomicUsize::new(0);
+    let server = StandIn::start(move |req| {
+        match (count.fetch_add(1, Ordering::SeqCst), req.header("range")) {
+            // first request: promise the whole file but drop the connection halfway
+            (0, None) => Reply::new(200)
+                .header("accept-ranges", "bytes")
+                .header("content-length", &served.len().to_string())
+                .body(&served[..1000]),
+            (_, Some(range)) => {
+                let start: usize = range
+                    .trim_start_matches("bytes=")
+                    .trim_end_matches('-')
+                    .parse()
+                    .unwrap();
+                Reply::new(206)
+                    .header(
+                        "content-range",
+                        &format!("bytes {start}-{}/{}", served.len() - 1, served.len()),
+                    )
+                    .body(&served[start..])
+            }
+            _ => Reply::new(500),
+        }
+    })
+    .await;
+
+    let dir = TempDir::new("write-file-resume");
+
+    let request = DownloadRequest {
+        url: server.url("/d/1"),
+        headers: HeaderMap::new(),
+        client: None,
+    };
+    let client = Client::default();
+    let resp = client
+        .get(&request.url, request.headers.clone())
+        .await
+        .unwrap();
+    let options = DownloadOptions {
+        verify: false,
+        ..Default::default()
+    };
+    write_file(
+        resp,
+        request,
+        &client,
+        dir.to_path_buf(),
+        "1".to_string(),
+        &options,
+        &Mutex::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(std::fs::read(dir.join("1.osz")).unwrap(), content);
+    assert!(!dir.join("1.osz.part").exists());
+    assert_eq!(server.requests()[1].header("range"), Some("bytes=1000-"));
+}
+
+#[tokio::test]
+async fn test_download_report() {
+    use crate::source::MirrorSource;
+    use crate::testing::{osz, Reply, StandIn, TempDir};
+
+    let server = StandIn::start(|req| match req.path.as_str() {
+        "/d/1" => Reply::new(200)
+            .header(
+                "content-disposition",
+                r#"attachment;filename="1 Art - Title.osz""#,
+            )
+            .body(osz(&["map.osu"])),
+        "/d/3" => Reply::new(200).body("<html>error</html>"),
+        _ => Reply::new(404),
+    })
+    .await;
+
+    let dir = TempDir::new("download-report");
+
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+    impl ProgressObserver for Recorder {
+        fn started(&self, sid: &str, total: u64, _: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("started {sid} {total}"));
+        }
+        fn advanced(&self, sid: &str, bytes: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("advanced {sid} {bytes}"));
+        }
+        fn finished(&self, sid: &str, _: &Path) {
+            self.0.lock().unwrap().push(format!("finished {sid}"));
+        }
+        fn failed(&self, sid: &str, _: &DownloadError) {
+            self.0.lock().unwrap().push(format!("failed {sid}"));
+        }
+    }
+
+    let recorder = Arc::new(Recorder::default());
+    let options = DownloadOptions {
+        progress: recorder.clone(),
+        file_name: FileNameTemplate::new("{sid} {artist} - {title}"),
+        ..Default::default()
+    };
+    let source = MirrorSource::new("local", server.url("/d/{sid}"));
+    let sid = vec!["2".to_string(), "1".to_string(), "3".to_string()];
+    let report = download_from(&sid, &source, &dir, &options).await.unwrap();
+
+    assert_eq!(report.entries[0].sid, "2");
+    assert_eq!(
+        report.entries[0].error().map(DownloadError::kind),
+        Some(&OsuMapDownloadError::NotFoundMapError)
+    );
+    let bytes = osz(&["map.osu"]).len() as u64;
+    assert_eq!(
+        report.entries[1].outcome,
+        DownloadOutcome::Saved {
+            path: dir.join("1 Art - Title.osz"),
+            bytes
+        }
+    );
+    // the html page is only caught when the archives are verified
+    #[cfg(feature = "verify")]
+    {
+        assert!(matches!(
+            report.entries[2].error().map(DownloadError::kind),
+            Some(OsuMapDownloadError::InvalidArchiveError { .. })
+        ));
+        assert!(!dir.join("3.osz").exists());
+        assert!(!dir.join("3.osz.part").exists());
+    }
+
+    let mut events = recorder.0.lock().unwrap().clone();
+    events.sort();
+    let mut expected = vec![
+        format!("advanced 1 {bytes}"),
+        "advanced 3 18".to_string(),
+        "failed 2".to_string(),
+        if cfg!(feature = "verify") {
+            "failed 3".to_string()
+        } else {
+            "finished 3".to_string()
+        },
+        "finished 1".to_string(),
+        format!("started 1 {bytes}"),
+        "started 3 18".to_string(),
+    ];
+    expected.sort();
+    assert_eq!(events, expected);
+}
+
+#[tokio::test]
+async fn test_download_retry_transient() {
+    use crate::source::MirrorSource;
+    use crate::testing::{Reply, StandIn, TempDir};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let count = AtomicUsize::new(0);
+    let server = StandIn::start(move |_| match count.fetch_add(1, Ordering::SeqCst) {
+        0 => Reply::new(503),
+        1 => Reply::new(429).header("retry-after", "0"),
+        _ => Reply::new(200).body(crate::testing::osz(&["map.osu"])),
+    })
+    .await;
+
+    let dir = TempDir::new("download-retry");
+
+    let source = MirrorSource::new("local", server.url("/d/{sid}"));
+    let options = DownloadOptions {
+        retry: RetryPolicy {
+            base_delay: Duration::from_millis(10),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let report = download_from(&["1".to_string()], &source, &dir, &options)
+        .await
+        .unwrap();
+
+    assert!(report.is_success());
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn test_download_bandwidth() {
+    use crate::source::MirrorSource;
+    use crate::testing::{Reply, StandIn, TempDir};
+
+    let server = StandIn::start(|_| Reply::new(200).body(vec![0u8; 3000])).await;
+    let dir = TempDir::new("download-bandwidth");
+
+    // two files of 3000 bytes share 4000 bytes per second, the second half waits
+    let source = MirrorSource::new("local", server.url("/d/{sid}"));
+    let options = DownloadOptions {
+        verify: false,
+        bandwidth: Some(Bandwidth::new(4000)),
+        bandwidth_per_download: Some(100_000),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let sid = vec!["1".to_string(), "2".to_string()];
+    let report = download_from(&sid, &source, &dir, &options).await.unwrap();
+    assert!(report.is_success());
+    assert!(started.elapsed() >= Duration::from_millis(400));
+}
+
+#[tokio::test]
+async fn test_download_cancel() {
+    use crate::source::MirrorSource;
+    use crate::testing::{osz, Reply, StandIn, TempDir};
+
+    let archive = osz(&["map.osu"]);
+    let size = archive.len() as u64;
+    let server = StandIn::start(move |_| Reply::new(200).body(archive.clone())).await;
+    let source = MirrorSource::new("local", server.url("/d/{sid}"));
+    let sid: Vec<String> = ["1", "2", "3"].iter().map(|s| s.to_string()).collect();
+
+    /// Cancel as soon as the first file starts being written.
+    struct CancelOnStart(CancelToken);
+
+    impl ProgressObserver for CancelOnStart {
+        fn started(&self, _sid: &str, _total: u64, _offset: u64) {
+            self.0.cancel();
+        }
+    }
+
+    for policy in [CancelPolicy::Abort, CancelPolicy::Finish] {
+        let dir = TempDir::new(&format!("download-cancel-{policy:?}"));
+
+        // when cancelled, one of 1 and 2 is being written and the other one has sent its
+        // request, 3 is waiting for a request slot. The bandwidth keeps the file open for
+        // about half a second.
+        let token = CancelToken::new();
+        let options = DownloadOptions {
+            max_requests: 2,
+            max_writes: 1,
+            bandwidth_per_download: Some(size * 2 / 3),
+            progress: Arc::new(CancelOnStart(token.clone())),
+            cancel: Some(token.clone()),
+            cancel_policy: policy,
+            ..Default::default()
+        };
+        let report = download_from(&sid, &source, &dir, &options).await.unwrap();
+
+        assert!(!report.is_success());
+        let not_started: Vec<&str> = report.not_started().map(|e| e.sid.as_str()).collect();
+        assert_eq!(not_started, ["3"]);
+        match policy {
+            CancelPolicy::Abort => {
+                assert_eq!(
+                    report.entries[0].error().map(DownloadError::kind),
+                    Some(&OsuMapDownloadError::Cancelled)
+                );
+                assert_eq!(
+                    report.entries[1].error().map(DownloadError::kind),
+                    Some(&OsuMapDownloadError::Cancelled)
+                );
+                for sid in ["1", "2"] {
+                    assert!(!dir.join(format!("{sid}.osz.part")).exists());
+                    assert!(!dir.join(format!("{sid}.osz")).exists());
+                }
+            }
+            CancelPolicy::Finish => {
+                assert!(report.entries[0].is_saved());
+                assert!(report.entries[1].is_saved());
+            }
+        }
+    }
+}
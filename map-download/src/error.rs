@@ -1,24 +1,332 @@
-use thiserror::Error;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 
-/// 不同类型的错误，在网络请求失败时使用
-#[derive(Debug, Clone, Error, PartialEq)]
+use crate::client::redact;
+
+/// 错误信息使用的语言
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    Chinese,
+    English,
+}
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(0);
+
+/// Set the language of every error message in this process, Chinese by default.
+pub fn set_language(language: Language) {
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+/// The language set by [`set_language`].
+pub fn language() -> Language {
+    match LANGUAGE.load(Ordering::Relaxed) {
+        1 => Language::English,
+        _ => Language::Chinese,
+    }
+}
+
+/// 错误的种类，可以直接 match，也可以用 [`code`](Self::code) 得到稳定的错误码。
+/// 出错的 sid、url 和状态码等信息在 [`DownloadError`] 中
+///
+/// New kinds may be added in any release, a `match` needs a `_` arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum OsuMapDownloadError {
-    #[error("验证失败,检查是否密码错误")]
     IncorrectPasswordError,
-    #[error("没有找到该谱面,或者已经下架或被删除,无法下载")]
     NotFoundMapError,
-    #[error("登录失败")]
     LoginFailError,
-    #[error("请求下载失败，可能是 Cookie 过期")]
     DownloadRequestError,
-    #[error("文件大小未知，可能出现网络问题")]
+    ConnectionError,
+    TimeoutError,
+    InvalidRequestError,
+    InvalidResponseError,
+    InvalidProxyError,
+    InvalidCertificateError { path: String },
+    ServerError { status: u16 },
+    TooManyRequestsError { retry_after: Option<u64> },
     UnknownSizeError,
-    #[error("无法创建下载文件路径：{path:?}，错误：{error:?}")]
-    TargetFileCreationError { path: String, error: String },
-    #[error("无法写入下载文件：{path:?}，错误：{error:?}")]
-    TargetFileWriteError { path: String, error: String },
-    #[error("网络出错，文件下载中断")]
+    TargetFileCreationError { path: String },
+    TargetFileWriteError { path: String },
     DownloadPartError,
-    #[error("其他异常")]
+    InvalidArchiveError { reason: String },
+    EmptyArchiveError,
+    InvalidSessionError,
+    UnsupportedSessionVersion { version: u64 },
+    Cancelled,
     Unknown,
 }
+
+impl OsuMapDownloadError {
+    /// A code for programs and logs, it never changes once released.
+    pub fn code(&self) -> &'static str {
+        use OsuMapDownloadError::*;
+        match self {
+            IncorrectPasswordError => "incorrect_password",
+            NotFoundMapError => "not_found",
+            LoginFailError => "login_failed",
+            DownloadRequestError => "request_rejected",
+            ConnectionError => "connection",
+            TimeoutError => "timeout",
+            InvalidRequestError => "invalid_request",
+            InvalidResponseError => "invalid_response",
+            InvalidProxyError => "invalid_proxy",
+            InvalidCertificateError { .. } => "invalid_certificate",
+            ServerError { .. } => "server_error",
+            TooManyRequestsError { .. } => "rate_limited",
+            UnknownSizeError => "unknown_size",
+            TargetFileCreationError { .. } => "file_create",
+            TargetFileWriteError { .. } => "file_write",
+            DownloadPartError => "interrupted",
+            InvalidArchiveError { .. } => "invalid_archive",
+            EmptyArchiveError => "empty_archive",
+            InvalidSessionError => "invalid_session",
+            UnsupportedSessionVersion { .. } => "unsupported_session_version",
+            Cancelled => "cancelled",
+            Unknown => "unknown",
+        }
+    }
+
+    pub fn message(&self, language: Language) -> String {
+        use OsuMapDownloadError::*;
+        match language {
+            Language::Chinese => match self {
+                IncorrectPasswordError => "验证失败,检查是否密码错误".to_string(),
+                NotFoundMapError => "没有找到该谱面,或者已经下架或被删除,无法下载".to_string(),
+                LoginFailError => "登录失败".to_string(),
+                DownloadRequestError => "请求下载失败，可能是 Cookie 过期".to_string(),
+                ConnectionError => "无法连接到服务器".to_string(),
+                TimeoutError => "等待响应超时".to_string(),
+                InvalidRequestError => "无法生成请求，请检查链接".to_string(),
+                InvalidResponseError => "无法解析服务器的响应".to_string(),
+                InvalidProxyError => "无法识别的代理地址".to_string(),
+                InvalidCertificateError { path } => format!("无法读取或识别证书：{path}"),
+                ServerError { status } => format!("服务器出错，状态码：{status}"),
+                TooManyRequestsError { .. } => "请求过于频繁，被服务器限流".to_string(),
+                UnknownSizeError => "文件大小未知，可能出现网络问题".to_string(),
+                TargetFileCreationError { path } => format!("无法创建下载文件：{path}"),
+                TargetFileWriteError { path } => format!("无法写入下载文件：{path}"),
+                DownloadPartError => "网络出错，文件下载中断".to_string(),
+                InvalidArchiveError { reason } => {
+                    format!("下载的文件不是有效的谱面压缩包：{reason}")
+                }
+                EmptyArchiveError => "下载的压缩包中没有 .osu 文件".to_string(),
+                InvalidSessionError => "无法识别保存的 session 数据，请重新登录".to_string(),
+                UnsupportedSessionVersion { version } => format!(
+                    "不支持的 session 数据版本：{version}，可能是由更新版本的程序保存的，请升级或重新登录"
+                ),
+                Cancelled => "下载已取消".to_string(),
+                Unknown => "其他异常".to_string(),
+            },
+            Language::English => match self {
+                IncorrectPasswordError => "authentication failed, check the password".to_string(),
+                NotFoundMapError => {
+                    "beatmapset not found, it may be deleted or unavailable for download"
+                        .to_string()
+                }
+                LoginFailError => "login failed".to_string(),
+                DownloadRequestError => {
+                    "download request rejected, the cookie may be expired".to_string()
+                }
+                ConnectionError => "can't connect to the server".to_string(),
+                TimeoutError => "timed out waiting for the response".to_string(),
+                InvalidRequestError => "can't build the request, check the url".to_string(),
+                InvalidResponseError => "can't parse the response of the server".to_string(),
+                InvalidProxyError => "invalid proxy url".to_string(),
+                InvalidCertificateError { path } => {
+                    format!("can't read or parse the certificate {path}")
+                }
+                ServerError { status } => format!("server error, status {status}"),
+                TooManyRequestsError { .. } => "too many requests, rate limited".to_string(),
+                UnknownSizeError => "unknown file size, maybe a network problem".to_string(),
+                TargetFileCreationError { path } => format!("can't create file {path}"),
+                TargetFileWriteError { path } => format!("can't write file {path}"),
+                DownloadPartError => "network error, download interrupted".to_string(),
+                InvalidArchiveError { reason } => {
+                    format!("not a valid beatmap archive: {reason}")
+                }
+                EmptyArchiveError => "the downloaded archive contains no .osu file".to_string(),
+                InvalidSessionError => {
+                    "can't read the saved session, please log in again".to_string()
+                }
+                UnsupportedSessionVersion { version } => format!(
+                    "unsupported session format version {version}, it may be saved by a newer \
+                     version, please upgrade or log in again"
+                ),
+                Cancelled => "download cancelled".to_string(),
+                Unknown => "unknown error".to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for OsuMapDownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message(language()))
+    }
+}
+
+impl StdError for OsuMapDownloadError {}
+
+/// 带上下文的错误：错误种类、出错的 sid、请求的 url、HTTP 状态码和底层的错误。
+/// url 中的密钥会被隐藏，Display 不包含 sid，因为报告里每条记录已经带有 sid，
+/// `{:#}` 会在后面附上 `source()` 链
+///
+/// The functions returning `anyhow::Result`, such as logging in, now wrap this type instead of
+/// [`OsuMapDownloadError`], so `downcast_ref::<OsuMapDownloadError>()` no longer finds it.
+/// Use `downcast_ref::<DownloadError>()` and [`kind`](Self::kind) instead.
+#[derive(Debug, Clone)]
+pub struct DownloadError {
+    kind: OsuMapDownloadError,
+    sid: Option<String>,
+    url: Option<String>,
+    status: Option<u16>,
+    source: Option<Arc<dyn StdError + Send + Sync>>,
+}
+
+impl DownloadError {
+    pub fn new(kind: OsuMapDownloadError) -> Self {
+        DownloadError {
+            kind,
+            sid: None,
+            url: None,
+            status: None,
+            source: None,
+        }
+    }
+
+    pub fn with_sid<T: Into<String>>(mut self, sid: T) -> Self {
+        self.sid = Some(sid.into());
+        self
+    }
+
+    /// The secrets in the url are hidden before it is stored.
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = Some(match reqwest::Url::parse(url) {
+            Ok(url) => redact(&url),
+            Err(_) => url.to_string(),
+        });
+        self
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// The underlying error, returned by `source()`.
+    pub fn with_source<E: Into<Box<dyn StdError + Send + Sync>>>(mut self, source: E) -> Self {
+        self.source = Some(Arc::from(source.into()));
+        self
+    }
+
+    pub fn kind(&self) -> &OsuMapDownloadError {
+        &self.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn sid(&self) -> Option<&str> {
+        self.sid.as_deref()
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// The message with the status code and url if any.
+    pub fn message(&self, language: Language) -> String {
+        let mut context = vec![];
+        match self.status {
+            // already in the message
+            Some(_) if matches!(self.kind, OsuMapDownloadError::ServerError { .. }) => (),
+            Some(status) => context.push(format!("HTTP {status}")),
+            None => (),
+        }
+        if let Some(url) = &self.url {
+            context.push(url.clone());
+        }
+        let message = self.kind.message(language);
+        match (language, context.is_empty()) {
+            (_, true) => message,
+            (Language::Chinese, false) => format!("{message}（{}）", context.join("，")),
+            (Language::English, false) => format!("{message} ({})", context.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message(language()))?;
+        if f.alternate() {
+            let mut source = self.source();
+            while let Some(e) = source {
+                write!(f, ": {e}")?;
+                source = e.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StdError for DownloadError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn StdError + 'static))
+    }
+}
+
+impl From<OsuMapDownloadError> for DownloadError {
+    fn from(kind: OsuMapDownloadError) -> Self {
+        DownloadError::new(kind)
+    }
+}
+
+/// The underlying error is not compared.
+impl PartialEq for DownloadError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.sid == other.sid
+            && self.url == other.url
+            && self.status == other.status
+    }
+}
+
+impl PartialEq<OsuMapDownloadError> for DownloadError {
+    fn eq(&self, other: &OsuMapDownloadError) -> bool {
+        self.kind == *other
+    }
+}
+
+#[test]
+fn test_download_error() {
+    let io = std::io::Error::other("reset by peer");
+    let error = DownloadError::new(OsuMapDownloadError::ServerError { status: 502 })
+        .with_sid("1")
+        .with_url("https://mirror.invalid/d/1?key=abc")
+        .with_status(502)
+        .with_source(io);
+
+    assert_eq!(error, OsuMapDownloadError::ServerError { status: 502 });
+    assert_eq!(error.code(), "server_error");
+    assert_eq!(error.sid(), Some("1"));
+    assert_eq!(error.source().unwrap().to_string(), "reset by peer");
+    assert_eq!(
+        error.message(Language::English),
+        "server error, status 502 (https://mirror.invalid/d/1?key=***)"
+    );
+    assert_eq!(
+        error.message(Language::Chinese),
+        "服务器出错，状态码：502（https://mirror.invalid/d/1?key=***）"
+    );
+    assert!(format!("{error:#}").ends_with("）: reset by peer"));
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::core::DownloadOptions;
+
+/// 已经存在的谱面：下载目录中以 sid 开头的 .osz 文件，以及 osu! Songs 目录中解压好的
+/// "{sid} Artist - Title" 文件夹
+#[derive(Debug, Default)]
+pub(crate) struct Existing {
+    found: HashMap<String, PathBuf>,
+}
+
+impl Existing {
+    /// Scan the directories once, so looking up a sid doesn't touch the disk again.
+    pub(crate) async fn scan(download_dir: &Path, options: &DownloadOptions) -> Self {
+        let mut found = HashMap::new();
+        if let Some(songs_dir) = &options.songs_dir {
+            scan_dir(songs_dir, false, &mut found).await;
+        }
+        if options.skip_existing {
+            scan_dir(download_dir, true, &mut found).await;
+        }
+
+        Existing { found }
+    }
+
+    /// Return the existing file or folder of the sid.
+    pub(crate) fn find(&self, sid: &str) -> Option<PathBuf> {
+        self.found.get(sid).cloned()
+    }
+}
+
+async fn scan_dir(dir: &Path, osz: bool, found: &mut HashMap<String, PathBuf>) {
+    let mut dir = match tokio::fs::read_dir(dir).await {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) if !osz || name.ends_with(".osz") => name,
+            _ => continue,
+        };
+        if let Some(sid) = leading_sid(name) {
+            found.insert(sid.to_string(), entry.path());
+        }
+    }
+}
+
+/// "1748483 Artist - Title" or "1748483.osz" -> "1748483"
+pub(crate) fn leading_sid(name: &str) -> Option<&str> {
+    let end = name.find(|c: char| !c.is_ascii_digit())?;
+    let (sid, rest) = name.split_at(end);
+    (!sid.is_empty() && (rest.starts_with(' ') || rest.starts_with('.'))).then_some(sid)
+}
+
+#[test]
+fn test_leading_sid() {
+    assert_eq!(leading_sid("1748483 Artist - Title"), Some("1748483"));
+    assert_eq!(leading_sid("1748483.osz"), Some("1748483"));
+    assert_eq!(leading_sid("beatmap-637 Artist - Title"), None);
+    assert_eq!(leading_sid("1748483"), None);
+}
+
+#[tokio::test]
+async fn test_find_existing() {
+    use crate::testing::TempDir;
+
+    let dir = TempDir::new("find-existing");
+    let songs = dir.join("Songs");
+    std::fs::create_dir_all(songs.join("2 Artist - Title")).unwrap();
+    std::fs::write(dir.join("1 Artist - Title.osz"), "").unwrap();
+    std::fs::write(dir.join("4.osz.part"), "").unwrap();
+
+    let options = DownloadOptions {
+        skip_existing: true,
+        songs_dir: Some(songs.clone()),
+        ..Default::default()
+    };
+    let existing = Existing::scan(&dir, &options).await;
+    assert_eq!(existing.find("1"), Some(dir.join("1 Artist - Title.osz")));
+    assert_eq!(existing.find("2"), Some(songs.join("2 Artist - Title")));
+    assert_eq!(existing.find("3"), None);
+    assert_eq!(existing.find("4"), None);
+
+    let existing = Existing::scan(&dir, &DownloadOptions::default()).await;
+    assert_eq!(existing.find("1"), None);
+}
@@ -1,12 +1,41 @@
+mod bandwidth;
+mod cancel;
 mod client;
 mod core;
 mod error;
+mod existing;
+mod naming;
+mod pool;
+mod progress;
+mod report;
+mod retry;
+mod source;
+#[cfg(any(test, feature = "mock-server"))]
+pub mod testing;
 #[cfg(feature = "unzip")]
 mod unzip;
 mod user;
+#[cfg(feature = "verify")]
+mod verify;
 
 /// A re-export module, user should only use this function
 pub mod prelude {
-    pub use crate::core::download;
-    pub use crate::user::UserSession;
+    pub use crate::bandwidth::Bandwidth;
+    pub use crate::cancel::{CancelPolicy, CancelToken};
+    pub use crate::client::{
+        set_host_rate_limit, set_rate_limit, Client, ClientConfig, RateLimit, Transport,
+    };
+    pub use crate::core::{download, download_from, DownloadOptions};
+    pub use crate::error::{set_language, DownloadError, Language, OsuMapDownloadError};
+    pub use crate::naming::{BeatmapsetMeta, FileNameTemplate, MetadataProvider};
+    pub use crate::pool::{PoolStrategy, SessionPool};
+    pub use crate::progress::{LogProgress, NoProgress, ProgressObserver};
+    pub use crate::report::{DownloadEntry, DownloadOutcome, DownloadReport};
+    pub use crate::retry::RetryPolicy;
+    pub use crate::source::{
+        DownloadRequest, DownloadSource, MirrorAuth, MirrorSource, NoVideoStyle, OfficialSource,
+    };
+    pub use crate::user::{
+        AuthorizationCode, ClientCredentials, OAuthSession, OAuthToken, SessionState, UserSession,
+    };
 }
@@ -0,0 +1,219 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// 文件名（不含扩展名）的最大字节数，给路径和 .osz.part 留出余量
+const MAX_STEM_BYTES: usize = 200;
+
+/// Windows 保留的设备名，不能作为文件名
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 谱面的基本信息，用来生成文件名
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BeatmapsetMeta {
+    pub artist: String,
+    pub title: String,
+    pub creator: String,
+}
+
+/// 查询谱面信息的接口，Content-Disposition 里没有需要的信息时使用
+#[async_trait]
+pub trait MetadataProvider: Send + Sync {
+    async fn beatmapset(&self, sid: &str) -> Result<BeatmapsetMeta>;
+}
+
+/// 文件名模板，可用的占位符：
+/// `{sid}` `{artist}` `{title}` `{creator}` `{novideo}`（不下载视频时为 " [no video]"）
+///
+/// 模板以 `{sid}` 开头时，跳过已存在的谱面 (`skip_existing`) 才能识别出这些文件。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileNameTemplate(String);
+
+impl Default for FileNameTemplate {
+    fn default() -> Self {
+        FileNameTemplate("{sid}.osz".to_string())
+    }
+}
+
+impl FileNameTemplate {
+    pub fn new<T: Into<String>>(template: T) -> Self {
+        FileNameTemplate(template.into())
+    }
+
+    /// Return true if the template needs anything besides the sid.
+    pub(crate) fn needs_meta(&self) -> bool {
+        ["{artist}", "{title}", "{creator}"]
+            .iter()
+            .any(|p| self.0.contains(p))
+    }
+
+    pub(crate) fn needs_creator(&self) -> bool {
+        self.0.contains("{creator}")
+    }
+
+    /// Fill the template and make it a safe file name. Fall back to `{sid}.osz` if the
+    /// template needs metadata that is not available.
+    pub(crate) fn render(
+        &self,
+        sid: &str,
+        meta: Option<&BeatmapsetMeta>,
+        no_video: bool,
+    ) -> String {
+        let name = match meta {
+            Some(meta) => self
+                .0
+                .replace("{artist}", &meta.artist)
+                .replace("{title}", &meta.title)
+                .replace("{creator}", &meta.creator),
+            None if self.needs_meta() => return Self::default().render(sid, None, no_video),
+            None => self.0.clone(),
+        };
+        let name = name
+            .replace("{sid}", sid)
+            .replace("{novideo}", if no_video { " [no video]" } else { "" });
+
+        let stem = name.strip_suffix(".osz").unwrap_or(&name);
+        format!("{}.osz", sanitize(stem))
+    }
+}
+
+/// Replace characters that are invalid on Windows, macOS or Linux, avoid reserved names and
+/// truncate the name to a safe length.
+pub(crate) fn sanitize(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    if name.len() > MAX_STEM_BYTES {
+        let mut end = MAX_STEM_BYTES;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+
+    // Windows doesn't allow trailing dots and spaces
+    let name = name.trim_end_matches(['.', ' ']).trim_start();
+    let base = name.split('.').next().unwrap_or_default();
+    if name.is_empty() || RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(base)) {
+        format!("_{name}")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Get the file name from a `Content-Disposition` header, `filename*` is preferred.
+pub(crate) fn content_disposition_filename(value: &str) -> Option<String> {
+    let mut plain = None;
+    for param in value.split(';').map(str::trim) {
+        if let Some(encoded) = param.strip_prefix("filename*=") {
+            // filename*=UTF-8''name%20with%20spaces.osz
+            let (_, encoded) = encoded.split_once("''")?;
+            return Some(percent_decode(encoded.trim_matches('"')));
+        }
+        if let Some(name) = param.strip_prefix("filename=") {
+            plain = Some(name.trim_matches('"').to_string());
+        }
+    }
+    plain
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// osu! names the file "{sid} {artist} - {title}.osz", with " [no video]" when the video
+/// is removed. The creator is not included.
+pub(crate) fn meta_from_filename(sid: &str, filename: &str) -> Option<BeatmapsetMeta> {
+    let stem = filename.strip_suffix(".osz").unwrap_or(filename);
+    let stem = stem.strip_suffix(" [no video]").unwrap_or(stem);
+    let rest = stem.strip_prefix(sid)?.strip_prefix(' ')?;
+    let (artist, title) = rest.split_once(" - ")?;
+    Some(BeatmapsetMeta {
+        artist: artist.to_string(),
+        title: title.to_string(),
+        creator: String::new(),
+    })
+}
+
+#[test]
+fn test_render_template() {
+    let meta = BeatmapsetMeta {
+        artist: "AC/DC".to_string(),
+        title: "Who? What*".to_string(),
+        creator: "peppy".to_string(),
+    };
+    let template = FileNameTemplate::new("{sid} {artist} - {title}{novideo}.osz");
+    assert_eq!(
+        template.render("1", Some(&meta), true),
+        "1 AC_DC - Who_ What_ [no video].osz"
+    );
+    assert_eq!(template.render("1", None, false), "1.osz");
+    assert_eq!(
+        FileNameTemplate::new("{creator}/{sid}").render("2", Some(&meta), false),
+        "peppy_2.osz"
+    );
+    assert_eq!(FileNameTemplate::default().render("3", None, true), "3.osz");
+}
+
+#[test]
+fn test_sanitize() {
+    assert_eq!(sanitize("con"), "_con");
+    assert_eq!(sanitize("Nul.txt"), "_Nul.txt");
+    assert_eq!(sanitize("title... "), "title");
+    assert_eq!(sanitize(""), "_");
+    let long = "あ".repeat(100);
+    let cut = sanitize(&long);
+    assert!(cut.len() <= MAX_STEM_BYTES && cut.chars().all(|c| c == 'あ'));
+}
+
+#[test]
+fn test_content_disposition() {
+    assert_eq!(
+        content_disposition_filename(r#"attachment;filename="1 A - B.osz""#),
+        Some("1 A - B.osz".to_string())
+    );
+    assert_eq!(
+        content_disposition_filename(
+            r#"attachment; filename="x.osz"; filename*=UTF-8''1%20%E3%81%82%20-%20B.osz"#
+        ),
+        Some("1 あ - B.osz".to_string())
+    );
+    assert_eq!(content_disposition_filename("inline"), None);
+
+    assert_eq!(
+        meta_from_filename("1", "1 Artist - Title - Remix [no video].osz"),
+        Some(BeatmapsetMeta {
+            artist: "Artist".to_string(),
+            title: "Title - Remix".to_string(),
+            creator: String::new(),
+        })
+    );
+    assert_eq!(meta_from_filename("1", "2 Artist - Title.osz"), None);
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use crate::error::OsuMapDownloadError;
+use crate::source::{official_request, DownloadRequest, DownloadSource};
+use crate::user::UserSession;
+
+/// 从账号池中挑选账号的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolStrategy {
+    /// Use the accounts one after another
+    #[default]
+    RoundRobin,
+    /// Use the account that has been idle for the longest time
+    LeastRecentlyUsed,
+}
+
+/// Why an account is not used for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bench {
+    /// Rate limited, usable again after the instant
+    Until(Instant),
+    /// The cookie is rejected, usable again after a successful refresh
+    Auth,
+}
+
+#[derive(Debug, Default)]
+struct AccountState {
+    last_used: Option<Instant>,
+    bench: Option<Bench>,
+}
+
+/// 多个账号组成的官网下载源，轮流使用各个账号下载，
+/// 被限流或者 cookie 失效的账号会暂停使用。每个账号用自己的 client 和 base url 下载
+#[derive(Debug)]
+pub struct SessionPool {
+    strategy: PoolStrategy,
+    bench_time: Duration,
+    sessions: Vec<RwLock<UserSession>>,
+    states: Mutex<Vec<AccountState>>,
+    next: AtomicUsize,
+    /// The account each sid in flight was sent with, removed once the attempt is over
+    assigned: Mutex<HashMap<String, usize>>,
+}
+
+impl SessionPool {
+    pub fn new(sessions: Vec<UserSession>) -> Self {
+        SessionPool {
+            strategy: PoolStrategy::default(),
+            bench_time: Duration::from_secs(60),
+            states: Mutex::new(sessions.iter().map(|_| AccountState::default()).collect()),
+            sessions: sessions.into_iter().map(RwLock::new).collect(),
+            next: AtomicUsize::new(0),
+            assigned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: PoolStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// How long a rate limited account rests when the server doesn't send `Retry-After`,
+    /// 60 seconds by default.
+    pub fn with_bench_time(mut self, bench_time: Duration) -> Self {
+        self.bench_time = bench_time;
+        self
+    }
+
+    /// Take back the sessions in the original order, so the cookies can be saved.
+    pub fn into_sessions(self) -> Vec<UserSession> {
+        self.sessions.into_iter().map(RwLock::into_inner).collect()
+    }
+
+    /// Pick an account that is not benched, wait if all of them are rate limited.
+    async fn pick(&self) -> Result<usize, OsuMapDownloadError> {
+        loop {
+            let wait = {
+                let mut states = self.states.lock().unwrap();
+                let now = Instant::now();
+                for state in states.iter_mut() {
+                    if matches!(state.bench, Some(Bench::Until(until)) if until <= now) {
+                        state.bench = None;
+                    }
+                }
+
+                if let Some(index) = self.choose(&states) {
+                    states[index].last_used = Some(now);
+                    return Ok(index);
+                }
+                states
+                    .iter()
+                    .filter_map(|s| match s.bench {
+                        Some(Bench::Until(until)) => Some(until - now),
+                        _ => None,
+                    })
+                    .min()
+                    .ok_or(OsuMapDownloadError::LoginFailError)?
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn choose(&self, states: &[AccountState]) -> Option<usize> {
+        let available = |i: &usize| states[*i].bench.is_none();
+        match self.strategy {
+            PoolStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..states.len())
+                    .map(|i| (start + i) % states.len())
+                    .find(available)
+            }
+            PoolStrategy::LeastRecentlyUsed => (0..states.len())
+                .filter(available)
+                .min_by_key(|i| states[*i].last_used),
+        }
+    }
+}
+
+#[async_trait]
+impl DownloadSource for SessionPool {
+    fn name(&self) -> &str {
+        "osu.ppy.sh"
+    }
+
+    async fn request(&self, sid: &str, no_video: bool) -> Result<DownloadRequest> {
+        // an error before the request is sent is not the fault of any account
+        self.assigned.lock().unwrap().remove(sid);
+        let index = self.pick().await?;
+        let session = self.sessions[index].read().await;
+        let mut request = official_request(session.base_url(), &session, sid, no_video)?;
+        request.client = Some(session.client().clone());
+        self.assigned.lock().unwrap().insert(sid.to_string(), index);
+        Ok(request)
+    }
+
+    /// Refresh the accounts whose cookie is rejected, return true if any account is usable.
+    async fn refresh(&self) -> Result<bool> {
+        let rejected: Vec<usize> = {
+            let states = self.states.lock().unwrap();
+            (0..states.len())
+                .filter(|i| states[*i].bench == Some(Bench::Auth))
+                .collect()
+        };
+        for index in rejected {
+            let mut session = self.sessions[index].write().await;
+            if session.has_password() && session.refresh().await.is_ok() {
+                self.states.lock().unwrap()[index].bench = None;
+            }
+        }
+
+        let states = self.states.lock().unwrap();
+        Ok(states.iter().any(|s| s.bench != Some(Bench::Auth)))
+    }
+
+    fn on_error(&self, sid: &str, error: &OsuMapDownloadError) {
+        let index = match self.assigned.lock().unwrap().remove(sid) {
+            Some(index) => index,
+            None => return,
+        };
+        let bench = match error {
+            OsuMapDownloadError::TooManyRequestsError { retry_after } => {
+                let rest = retry_after.map_or(self.bench_time, Duration::from_secs);
+                Bench::Until(Instant::now() + rest)
+            }
+            OsuMapDownloadError::DownloadRequestError | OsuMapDownloadError::LoginFailError => {
+                Bench::Auth
+            }
+            _ => return,
+        };
+        self.states.lock().unwrap()[index].bench = Some(bench);
+    }
+
+    fn on_success(&self, sid: &str) {
+        self.assigned.lock().unwrap().remove(sid);
+    }
+}
+
+#[tokio::test]
+async fn test_pool_strategy() {
+    let sessions = || {
+        ["a,1", "b,2", "c,3"]
+            .iter()
+            .map(|data| UserSession::from_recoverable("foo", data).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let pool = SessionPool::new(sessions());
+    let mut picked = vec![];
+    for _ in 0..4 {
+        picked.push(pool.pick().await.unwrap());
+    }
+    assert_eq!(picked, [0, 1, 2, 0]);
+
+    let pool = SessionPool::new(sessions()).with_strategy(PoolStrategy::LeastRecentlyUsed);
+    pool.states.lock().unwrap()[0].last_used = Some(Instant::now());
+    assert_eq!(pool.pick().await.unwrap(), 1);
+    assert_eq!(pool.pick().await.unwrap(), 2);
+    assert_eq!(pool.pick().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_pool_bench() {
+    let pool = SessionPool::new(vec![
+        UserSession::from_recoverable("foo", "a,1").unwrap(),
+        UserSession::from_recoverable("bar", "b,2").unwrap(),
+    ])
+    .with_bench_time(Duration::from_millis(100));
+
+    let request = pool.request("1", false).await.unwrap();
+    assert!(request.headers["cookie"]
+        .to_str()
+        .unwrap()
+        .contains("osu_session=1"));
+    pool.on_error(
+        "1",
+        &OsuMapDownloadError::TooManyRequestsError { retry_after: None },
+    );
+
+    // the rate limited account rests, the other one is rejected
+    let request = pool.request("2", false).await.unwrap();
+    assert!(request.headers["cookie"]
+        .to_str()
+        .unwrap()
+        .contains("osu_session=2"));
+    pool.on_error("2", &OsuMapDownloadError::DownloadRequestError);
+
+    // wait for the first account instead of failing
+    let started = Instant::now();
+    assert_eq!(pool.pick().await.unwrap(), 0);
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    // no password to refresh the second account, but the first one is still usable
+    assert!(pool.refresh().await.unwrap());
+    pool.request("1", false).await.unwrap();
+    pool.on_error("1", &OsuMapDownloadError::LoginFailError);
+    assert!(!pool.refresh().await.unwrap());
+    assert_eq!(
+        pool.pick().await.unwrap_err(),
+        OsuMapDownloadError::LoginFailError
+    );
+
+    // a request that fails before picking an account doesn't bench the one used last time,
+    // and nothing is kept for the finished sids
+    assert!(pool.request("1", false).await.is_err());
+    pool.on_error("1", &OsuMapDownloadError::DownloadRequestError);
+    assert!(pool.assigned.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_pool_clients() {
+    use crate::core::{download_from, DownloadOptions};
+    use crate::testing::{MockOsu, TempDir};
+
+    // two accounts on two servers, each one downloads through its own client
+    let start = || async {
+        MockOsu::start()
+            .await
+            .with_account("foo", "bar")
+            .with_beatmapset(1, &[11])
+            .with_beatmapset(2, &[21])
+    };
+    let (first, second) = (start().await, start().await);
+    let pool = SessionPool::new(vec![
+        UserSession::new_with_client(first.client(), "foo", "bar")
+            .await
+            .unwrap(),
+        UserSession::new_with_client(second.client(), "foo", "bar")
+            .await
+            .unwrap(),
+    ]);
+
+    let dir = TempDir::new("pool-clients");
+    let options = DownloadOptions {
+        max_requests: 1,
+        ..Default::default()
+    };
+    let sid = vec!["1".to_string(), "2".to_string()];
+    let report = download_from(&sid, &pool, &dir, &options).await.unwrap();
+    assert!(report.is_success());
+    assert!(pool.assigned.lock().unwrap().is_empty());
+    let downloads = |osu: &MockOsu| {
+        osu.requests()
+            .iter()
+            .filter(|r| r.path.contains("/download"))
+            .count()
+    };
+    assert_eq!(downloads(&first), 1);
+    assert_eq!(downloads(&second), 1);
+}
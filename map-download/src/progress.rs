@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use tracing::{info, warn};
+
+use crate::error::DownloadError;
+
+/// 下载进度的回调，所有方法都有空的默认实现，按需实现即可
+///
+/// A sid may be started more than once when it is retried or resumed, `offset` is the size
+/// already on disk. `failed` is only called once, after all the retries are used up.
+pub trait ProgressObserver: Send + Sync {
+    /// The response arrived and the file starts being written.
+    fn started(&self, _sid: &str, _total: u64, _offset: u64) {}
+
+    /// `bytes` more bytes are written to disk.
+    fn advanced(&self, _sid: &str, _bytes: u64) {}
+
+    /// The file is saved to `path`.
+    fn finished(&self, _sid: &str, _path: &Path) {}
+
+    /// The sid couldn't be downloaded.
+    fn failed(&self, _sid: &str, _error: &DownloadError) {}
+}
+
+/// 不输出任何进度
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+/// 每个谱面开始、完成、失败时记录一条 tracing 事件，适合写入日志
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogProgress;
+
+impl ProgressObserver for LogProgress {
+    fn started(&self, sid: &str, total: u64, offset: u64) {
+        if offset > 0 {
+            info!(sid, offset, total, "download resumed");
+        } else {
+            info!(sid, total, "download started");
+        }
+    }
+
+    fn finished(&self, sid: &str, path: &Path) {
+        info!(sid, path = %path.display(), "download saved");
+    }
+
+    fn failed(&self, sid: &str, error: &DownloadError) {
+        warn!(sid, error = %error, "download failed");
+    }
+}
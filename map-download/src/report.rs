@@ -0,0 +1,159 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::error::{language, DownloadError, Language};
+
+/// 单个谱面的下载结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadOutcome {
+    /// The beatmapset is saved to `path`, `bytes` is the size of the file
+    Saved { path: PathBuf, bytes: u64 },
+    /// The beatmapset already exists at `path`, nothing is downloaded
+    Skipped { path: PathBuf },
+    /// The beatmapset couldn't be downloaded
+    Failed(DownloadError),
+    /// The download was cancelled before this sid started
+    NotStarted,
+}
+
+/// 报告中的一条记录，对应一个 sid
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadEntry {
+    pub sid: String,
+    /// Time spent on this sid, from sending the request to finishing the file
+    pub duration: Duration,
+    pub outcome: DownloadOutcome,
+}
+
+impl DownloadEntry {
+    pub fn is_saved(&self) -> bool {
+        matches!(self.outcome, DownloadOutcome::Saved { .. })
+    }
+
+    /// The error of this entry, if it failed.
+    pub fn error(&self) -> Option<&DownloadError> {
+        match &self.outcome {
+            DownloadOutcome::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// 输出使用 [`set_language`](crate::error::set_language) 设置的语言
+impl fmt::Display for DownloadEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sid = &self.sid;
+        let secs = self.duration.as_secs_f64();
+        match (&self.outcome, language()) {
+            (DownloadOutcome::Saved { path, bytes }, Language::Chinese) => write!(
+                f,
+                "{sid}: 已保存到 {} ({bytes} bytes, {secs:.1}s)",
+                path.display()
+            ),
+            (DownloadOutcome::Saved { path, bytes }, Language::English) => write!(
+                f,
+                "{sid}: saved to {} ({bytes} bytes, {secs:.1}s)",
+                path.display()
+            ),
+            (DownloadOutcome::Skipped { path }, Language::Chinese) => {
+                write!(f, "{sid}: 已存在 {}, 跳过", path.display())
+            }
+            (DownloadOutcome::Skipped { path }, Language::English) => {
+                write!(f, "{sid}: already exists at {}, skipped", path.display())
+            }
+            (DownloadOutcome::Failed(e), Language::Chinese) => write!(f, "{sid}: 下载失败, {e:#}"),
+            (DownloadOutcome::Failed(e), Language::English) => write!(f, "{sid}: failed, {e:#}"),
+            (DownloadOutcome::NotStarted, Language::Chinese) => {
+                write!(f, "{sid}: 已取消, 没有开始下载")
+            }
+            (DownloadOutcome::NotStarted, Language::English) => {
+                write!(f, "{sid}: cancelled before starting")
+            }
+        }
+    }
+}
+
+/// 一次下载的结果汇总，每个 sid 一条记录，顺序和传入的 sid 一致，重复的 sid 只有一条
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadReport {
+    pub entries: Vec<DownloadEntry>,
+}
+
+impl DownloadReport {
+    /// Entries that have been saved to disk.
+    pub fn saved(&self) -> impl Iterator<Item = &DownloadEntry> {
+        self.entries.iter().filter(|e| e.is_saved())
+    }
+
+    /// Entries that already exist and are skipped.
+    pub fn skipped(&self) -> impl Iterator<Item = &DownloadEntry> {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.outcome, DownloadOutcome::Skipped { .. }))
+    }
+
+    /// Entries that failed.
+    pub fn failed(&self) -> impl Iterator<Item = &DownloadEntry> {
+        self.entries.iter().filter(|e| e.error().is_some())
+    }
+
+    /// Entries that never ran because the download was cancelled.
+    pub fn not_started(&self) -> impl Iterator<Item = &DownloadEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome == DownloadOutcome::NotStarted)
+    }
+
+    /// Return true if every sid is saved or skipped.
+    pub fn is_success(&self) -> bool {
+        self.entries.iter().all(|e| {
+            matches!(
+                e.outcome,
+                DownloadOutcome::Saved { .. } | DownloadOutcome::Skipped { .. }
+            )
+        })
+    }
+
+    /// Total bytes written to disk.
+    pub fn total_bytes(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|e| match e.outcome {
+                DownloadOutcome::Saved { bytes, .. } => bytes,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+#[test]
+fn test_report_summary() {
+    use crate::error::OsuMapDownloadError;
+
+    let report = DownloadReport {
+        entries: vec![
+            DownloadEntry {
+                sid: "1".to_string(),
+                duration: Duration::from_secs(1),
+                outcome: DownloadOutcome::Saved {
+                    path: PathBuf::from("1.osz"),
+                    bytes: 100,
+                },
+            },
+            DownloadEntry {
+                sid: "2".to_string(),
+                duration: Duration::ZERO,
+                outcome: DownloadOutcome::Failed(
+                    DownloadError::new(OsuMapDownloadError::NotFoundMapError).with_sid("2"),
+                ),
+            },
+        ],
+    };
+
+    assert!(!report.is_success());
+    assert_eq!(report.saved().count(), 1);
+    assert_eq!(report.failed().next().unwrap().sid, "2");
+    assert_eq!(report.entries[1].error().unwrap().code(), "not_found");
+    assert_eq!(report.total_bytes(), 100);
+}
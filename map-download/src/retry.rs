@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+use crate::error::OsuMapDownloadError;
+
+/// 临时性错误的重试策略，每个 sid 单独计算重试次数
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Max attempts of a single sid, including the first one. `1` disables retrying.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every following retry
+    pub base_delay: Duration,
+    /// Upper bound of a single delay. A longer `Retry-After` from the server is not retried.
+    pub max_delay: Duration,
+    /// No retry is started once this much time has passed since the download began
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            deadline: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Return how long to wait before the next attempt, or `None` if the error is not
+    /// transient, the attempts are used up, the server asks to wait longer than `max_delay`,
+    /// or waiting would pass the deadline.
+    /// `attempt` is the number of the attempt that just failed, starting from 1.
+    pub(crate) fn delay(
+        &self,
+        error: &OsuMapDownloadError,
+        attempt: u32,
+        started: Instant,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = match error {
+            OsuMapDownloadError::TooManyRequestsError {
+                retry_after: Some(secs),
+            } => {
+                let wait = Duration::from_secs(*secs);
+                if wait > self.max_delay {
+                    return None;
+                }
+                wait
+            }
+            OsuMapDownloadError::TooManyRequestsError { retry_after: None }
+            | OsuMapDownloadError::ServerError { .. }
+            | OsuMapDownloadError::ConnectionError
+            | OsuMapDownloadError::TimeoutError
+            | OsuMapDownloadError::DownloadPartError => self.backoff(attempt),
+            _ => return None,
+        };
+
+        match self.deadline {
+            Some(deadline) if started.elapsed() + delay > deadline => None,
+            _ => Some(delay),
+        }
+    }
+
+    /// Exponential backoff with jitter: half of the delay is fixed, the other half random.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        let half = exp / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// Read `Retry-After` in seconds, both delay-seconds and HTTP-date are accepted.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .map_or(0, |d| d.as_secs()),
+    )
+}
+
+#[test]
+fn test_retry_delay() {
+    let policy = RetryPolicy {
+        max_attempts: 4,
+        base_delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(5),
+        deadline: None,
+    };
+    let now = Instant::now();
+    let server = OsuMapDownloadError::ServerError { status: 502 };
+
+    let first = policy.delay(&server, 1, now).unwrap();
+    assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+    let third = policy.delay(&server, 3, now).unwrap();
+    assert!(third >= Duration::from_millis(2500) && third <= Duration::from_secs(5));
+    assert_eq!(policy.delay(&server, 4, now), None);
+
+    let limited = OsuMapDownloadError::TooManyRequestsError {
+        retry_after: Some(3),
+    };
+    assert_eq!(policy.delay(&limited, 1, now), Some(Duration::from_secs(3)));
+    let too_long = OsuMapDownloadError::TooManyRequestsError {
+        retry_after: Some(6),
+    };
+    assert_eq!(policy.delay(&too_long, 1, now), None);
+    assert_eq!(
+        policy.delay(&OsuMapDownloadError::NotFoundMapError, 1, now),
+        None
+    );
+
+    let policy = RetryPolicy {
+        deadline: Some(Duration::from_secs(1)),
+        ..policy
+    };
+    assert_eq!(policy.delay(&limited, 1, now), None);
+}
+
+#[test]
+fn test_retry_after_header() {
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, "120".parse().unwrap());
+    assert_eq!(retry_after(&headers), Some(120));
+
+    headers.insert(
+        RETRY_AFTER,
+        "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+    );
+    assert_eq!(retry_after(&headers), Some(0));
+
+    headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+    assert_eq!(retry_after(&headers), None);
+}
@@ -0,0 +1,304 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use tokio::sync::RwLock;
+
+use crate::client::Client;
+use crate::error::{DownloadError, OsuMapDownloadError};
+use crate::user::{OAuthSession, UserSession};
+
+/// 一次下载请求所需要的 url 和 headers
+#[derive(Debug, Clone)]
+pub struct DownloadRequest {
+    pub url: String,
+    pub headers: HeaderMap,
+    /// Send this request with the given client instead of [`DownloadSource::client`],
+    /// [`DownloadOptions::client`](crate::core::DownloadOptions) still takes precedence.
+    pub client: Option<Client>,
+}
+
+/// 谱面下载源，官网或者镜像站
+#[async_trait]
+pub trait DownloadSource: Send + Sync {
+    /// Name of this source, used in messages.
+    fn name(&self) -> &str;
+
+    /// Build the request for downloading the given beatmapset.
+    async fn request(&self, sid: &str, no_video: bool) -> Result<DownloadRequest>;
+
+    /// Try to re-authenticate after a failed download. Return `false` when the source
+    /// has nothing to refresh, so the caller won't retry.
+    async fn refresh(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Called every time a download of `sid` fails, including the attempts that are retried.
+    fn on_error(&self, _sid: &str, _error: &OsuMapDownloadError) {}
+
+    /// Called when `sid` is saved.
+    fn on_success(&self, _sid: &str) {}
+
+    /// The client the downloads should be sent with, `None` for the default client.
+    /// [`DownloadOptions::client`](crate::core::DownloadOptions) takes precedence.
+    fn client(&self) -> Option<&Client> {
+        None
+    }
+}
+
+/// 官网下载源，使用 UserSession 的 cookie 下载
+#[derive(Debug)]
+pub struct OfficialSource {
+    client: Client,
+    session: RwLock<UserSession>,
+}
+
+impl OfficialSource {
+    pub fn new(session: UserSession) -> Self {
+        OfficialSource {
+            client: session.client().clone(),
+            session: RwLock::new(session),
+        }
+    }
+
+    /// Take back the inner session, so the refreshed cookie can be saved.
+    pub fn into_session(self) -> UserSession {
+        self.session.into_inner()
+    }
+}
+
+/// The website download request with the cookie of `session`.
+pub(crate) fn official_request(
+    base_url: &str,
+    session: &UserSession,
+    sid: &str,
+    no_video: bool,
+) -> Result<DownloadRequest, DownloadError> {
+    let mut url = format!("{base_url}/beatmapsets/{sid}/download");
+    if no_video {
+        url.push_str("?noVideo=1");
+    }
+    let headers = session.new_header(&format!("{base_url}/beatmapsets/{sid}"))?;
+    Ok(DownloadRequest {
+        url,
+        headers,
+        client: None,
+    })
+}
+
+#[async_trait]
+impl DownloadSource for OfficialSource {
+    fn name(&self) -> &str {
+        "osu.ppy.sh"
+    }
+
+    async fn request(&self, sid: &str, no_video: bool) -> Result<DownloadRequest> {
+        let session = self.session.read().await;
+        Ok(official_request(
+            session.base_url(),
+            &session,
+            sid,
+            no_video,
+        )?)
+    }
+
+    async fn refresh(&self) -> Result<bool> {
+        self.session.write().await.refresh().await?;
+        Ok(true)
+    }
+
+    fn client(&self) -> Option<&Client> {
+        Some(&self.client)
+    }
+}
+
+/// 通过 osu! API v2 下载，使用 OAuth 登录的 token，OAuth 应用需要有下载谱面的权限
+#[async_trait]
+impl DownloadSource for OAuthSession {
+    fn name(&self) -> &str {
+        "osu! API v2"
+    }
+
+    async fn request(&self, sid: &str, no_video: bool) -> Result<DownloadRequest> {
+        let mut url = format!("{}/api/v2/beatmapsets/{sid}/download", self.base_url());
+        if no_video {
+            url.push_str("?noVideo=1");
+        }
+        Ok(DownloadRequest {
+            url,
+            headers: self.auth_header().await?,
+            client: None,
+        })
+    }
+
+    async fn refresh(&self) -> Result<bool> {
+        OAuthSession::refresh(self).await?;
+        Ok(true)
+    }
+
+    fn client(&self) -> Option<&Client> {
+        Some(OAuthSession::client(self))
+    }
+}
+
+/// 镜像站如何表示 "不下载视频"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoVideoStyle {
+    /// The mirror has no such option, the parameter is ignored
+    Unsupported,
+    /// Append `key=value` to the query string, e.g. `?nv=1`
+    Query { key: String, value: String },
+    /// Replace `{novideo}` in the url template with `on` or `off`
+    Placeholder { on: String, off: String },
+}
+
+/// 镜像站的认证方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorAuth {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// An arbitrary header, e.g. an api key
+    Header { name: String, value: String },
+}
+
+/// 镜像站下载源，通过 url 模板生成下载链接，模板里的 `{sid}` 会被替换成谱面 sid
+#[derive(Debug, Clone)]
+pub struct MirrorSource {
+    name: String,
+    template: String,
+    no_video: NoVideoStyle,
+    auth: Option<MirrorAuth>,
+}
+
+impl MirrorSource {
+    pub fn new<T: Into<String>, U: Into<String>>(name: T, template: U) -> Self {
+        MirrorSource {
+            name: name.into(),
+            template: template.into(),
+            no_video: NoVideoStyle::Unsupported,
+            auth: None,
+        }
+    }
+
+    /// https://nerinyan.moe
+    pub fn nerinyan() -> Self {
+        Self::new("nerinyan", "https://api.nerinyan.moe/d/{sid}").with_no_video(
+            NoVideoStyle::Query {
+                key: "nv".to_string(),
+                value: "1".to_string(),
+            },
+        )
+    }
+
+    /// https://catboy.best
+    pub fn catboy() -> Self {
+        Self::new("catboy", "https://catboy.best/d/{sid}{novideo}").with_no_video(
+            NoVideoStyle::Placeholder {
+                on: "n".to_string(),
+                off: String::new(),
+            },
+        )
+    }
+
+    pub fn with_no_video(mut self, style: NoVideoStyle) -> Self {
+        self.no_video = style;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: MirrorAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    fn url(&self, sid: &str, no_video: bool) -> String {
+        let url = self.template.replace("{sid}", sid);
+        match &self.no_video {
+            NoVideoStyle::Unsupported => url.replace("{novideo}", ""),
+            NoVideoStyle::Placeholder { on, off } => {
+                url.replace("{novideo}", if no_video { on } else { off })
+            }
+            NoVideoStyle::Query { key, value } if no_video => {
+                let sep = if url.contains('?') { '&' } else { '?' };
+                format!("{url}{sep}{key}={value}")
+            }
+            NoVideoStyle::Query { .. } => url,
+        }
+    }
+}
+
+#[async_trait]
+impl DownloadSource for MirrorSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn request(&self, sid: &str, no_video: bool) -> Result<DownloadRequest> {
+        let mut headers = HeaderMap::new();
+        match &self.auth {
+            Some(MirrorAuth::Bearer(token)) => {
+                headers.insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+            }
+            Some(MirrorAuth::Header { name, value }) => {
+                headers.insert(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(value)?,
+                );
+            }
+            None => (),
+        }
+
+        Ok(DownloadRequest {
+            url: self.url(sid, no_video),
+            headers,
+            client: None,
+        })
+    }
+}
+
+#[test]
+fn test_mirror_url_style() {
+    let mirror = MirrorSource::nerinyan();
+    assert_eq!(
+        mirror.url("114", true),
+        "https://api.nerinyan.moe/d/114?nv=1"
+    );
+    assert_eq!(mirror.url("114", false), "https://api.nerinyan.moe/d/114");
+
+    let mirror = MirrorSource::catboy();
+    assert_eq!(mirror.url("114", true), "https://catboy.best/d/114n");
+    assert_eq!(mirror.url("114", false), "https://catboy.best/d/114");
+
+    let mirror = MirrorSource::new("plain", "https://example.com/{sid}{novideo}.osz");
+    assert_eq!(mirror.url("514", true), "https://example.com/514.osz");
+}
+
+#[tokio::test]
+async fn test_sources_against_stand_in() {
+    use crate::testing::{Reply, StandIn};
+
+    let server = StandIn::start(|_| Reply::new(200).body("osz")).await;
+
+    let mirror = MirrorSource::new("local", server.url("/d/{sid}"))
+        .with_no_video(NoVideoStyle::Query {
+            key: "nv".to_string(),
+            value: "1".to_string(),
+        })
+        .with_auth(MirrorAuth::Bearer("secret".to_string()));
+    let req = mirror.request("1001", true).await.unwrap();
+    let resp = Client::default().get(&req.url, req.headers).await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "osz");
+
+    let session = UserSession::from_recoverable("foo", "xsrf,sess")
+        .unwrap()
+        .with_base_url(server.url(""))
+        .unwrap();
+    let official = OfficialSource::new(session);
+    let req = official.request("1002", false).await.unwrap();
+    Client::default().get(&req.url, req.headers).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests[0].path, "/d/1001?nv=1");
+    assert_eq!(requests[0].header("authorization"), Some("Bearer secret"));
+    assert_eq!(requests[1].path, "/beatmapsets/1002/download");
+    let cookie = requests[1].header("cookie").unwrap();
+    assert!(cookie.contains("XSRF-TOKEN=xsrf") && cookie.contains("osu_session=sess"));
+}
@@ -0,0 +1,261 @@
+//! 模拟 osu! 官网的本地服务，登录、bid 跳转和下载的流程都可以离线测试
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use reqwest::Url;
+
+use super::{osz, Reply, Request, StandIn};
+use crate::client::{Client, ClientConfig};
+
+/// 下载请求时模拟的故障，按 [`MockOsu::fail_next`] 的顺序依次生效
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Promise the whole file but close the connection halfway
+    Truncated,
+    /// Drop every logged in session before answering, like the cookie expired
+    ExpireSessions,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// username -> password
+    accounts: HashMap<String, String>,
+    /// osu_session of the logged in users -> username
+    sessions: HashMap<String, String>,
+    /// bid -> sid
+    beatmaps: HashMap<String, String>,
+    /// sid -> archive
+    archives: HashMap<String, Vec<u8>>,
+    failures: HashMap<String, VecDeque<Failure>>,
+    issued: usize,
+    logins: usize,
+}
+
+impl State {
+    fn token(&mut self, prefix: &str) -> String {
+        self.issued += 1;
+        format!("{prefix}-{}", self.issued)
+    }
+
+    fn user(&self, req: &Request) -> Option<&String> {
+        cookie(req, "osu_session").and_then(|s| self.sessions.get(&s))
+    }
+}
+
+/// 模拟的 osu! 官网，包含 `/home`、`/session`、`/home/account/edit`、
+/// `/b/{bid}` 跳转和 `/beatmapsets/{sid}/download`
+pub struct MockOsu {
+    server: StandIn,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockOsu {
+    pub async fn start() -> MockOsu {
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        let server = StandIn::start(move |req| handle(&mut shared.lock().unwrap(), req)).await;
+        MockOsu { server, state }
+    }
+
+    pub fn with_account(self, username: &str, password: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(username.to_string(), password.to_string());
+        self
+    }
+
+    /// Add a beatmapset with its difficulties, the archive contains one .osu per bid.
+    pub fn with_beatmapset(self, sid: u32, bids: &[u32]) -> Self {
+        let files: Vec<String> = bids.iter().map(|bid| format!("{bid}.osu")).collect();
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+        let mut state = self.state.lock().unwrap();
+        state.archives.insert(sid.to_string(), osz(&files));
+        for bid in bids {
+            state.beatmaps.insert(bid.to_string(), sid.to_string());
+        }
+        drop(state);
+        self
+    }
+
+    /// Fail the next download of the sid, call again to queue more failures.
+    pub fn fail_next(&self, sid: u32, failure: Failure) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(sid.to_string())
+            .or_default()
+            .push_back(failure);
+    }
+
+    /// Log out everyone, the saved cookies stop working.
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().sessions.clear();
+    }
+
+    /// Number of successful logins so far
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    pub fn archive(&self, sid: u32) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .archives
+            .get(&sid.to_string())
+            .cloned()
+    }
+
+    /// The `ETag` sent with the archive of `sid`
+    pub fn etag(&self, sid: u32) -> Option<String> {
+        self.archive(sid).map(|archive| etag(&archive))
+    }
+
+    /// Stands for `https://osu.ppy.sh`
+    pub fn base_url(&self) -> String {
+        self.server.url("")
+    }
+
+    /// A client whose sessions talk to this server.
+    pub fn client(&self) -> Client {
+        ClientConfig::new()
+            .with_base_url(self.base_url())
+            .build()
+            .unwrap()
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.server.requests()
+    }
+}
+
+fn etag(archive: &[u8]) -> String {
+    let hash = archive
+        .iter()
+        .fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(u32::from(*b)));
+    format!("\"{:x}-{hash:x}\"", archive.len())
+}
+
+fn cookie(req: &Request, name: &str) -> Option<String> {
+    req.header("cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+}
+
+fn set_cookie(reply: Reply, name: &str, value: &str) -> Reply {
+    reply.header("set-cookie", &format!("{name}={value}; path=/; httponly"))
+}
+
+fn handle(state: &mut State, req: &Request) -> Reply {
+    let path = req.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["home"]) => home(state, req),
+        ("POST", ["session"]) => login(state, req),
+        ("GET", ["home", "account", "edit"]) => match state.user(req) {
+            Some(_) => Reply::new(200).body("account"),
+            None => Reply::new(302).header("location", "/home"),
+        },
+        ("GET", ["b", bid]) => match state.beatmaps.get(*bid) {
+            Some(sid) => {
+                Reply::new(302).header("location", &format!("/beatmapsets/{sid}#osu/{bid}"))
+            }
+            None => Reply::new(404),
+        },
+        ("GET", ["beatmapsets", sid]) if state.archives.contains_key(*sid) => {
+            Reply::new(200).body("beatmapset")
+        }
+        ("GET", ["beatmapsets", sid, "download"]) => download(state, req, sid),
+        _ => Reply::new(404),
+    }
+}
+
+/// Hand out a fresh XSRF token, guests also get a session cookie.
+fn home(state: &mut State, req: &Request) -> Reply {
+    let xsrf = state.token("xsrf");
+    let reply = set_cookie(Reply::new(200).body("home"), "XSRF-TOKEN", &xsrf);
+    if state.user(req).is_some() {
+        return reply;
+    }
+    let guest = state.token("guest");
+    set_cookie(reply, "osu_session", &guest)
+}
+
+fn login(state: &mut State, req: &Request) -> Reply {
+    let query = format!("http://form/?{}", String::from_utf8_lossy(&req.body));
+    let form: HashMap<String, String> = match Url::parse(&query) {
+        Ok(url) => url.query_pairs().into_owned().collect(),
+        Err(_) => return Reply::new(400),
+    };
+    let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+
+    // page expired, like the real site when the token doesn't match
+    if cookie(req, "XSRF-TOKEN").as_deref() != Some(field("_token")) {
+        return Reply::new(419);
+    }
+    if state.accounts.get(field("username")).map(String::as_str) != Some(field("password")) {
+        return Reply::new(403);
+    }
+
+    let session = state.token("session");
+    state
+        .sessions
+        .insert(session.clone(), field("username").to_string());
+    state.logins += 1;
+    set_cookie(Reply::new(200).body("{}"), "osu_session", &session)
+}
+
+fn download(state: &mut State, req: &Request, sid: &str) -> Reply {
+    let failure = state.failures.get_mut(sid).and_then(VecDeque::pop_front);
+    if failure == Some(Failure::ExpireSessions) {
+        state.sessions.clear();
+    }
+    if state.user(req).is_none() {
+        return Reply::new(403);
+    }
+    let archive = match state.archives.get(sid) {
+        Some(archive) => archive,
+        None => return Reply::new(404),
+    };
+
+    // a range for an older version of the archive is ignored
+    let etag = etag(archive);
+    let start: usize = req
+        .header("range")
+        .filter(|_| req.header("if-range").is_none_or(|v| v == etag))
+        .and_then(|r| {
+            r.trim_start_matches("bytes=")
+                .trim_end_matches('-')
+                .parse()
+                .ok()
+        })
+        .unwrap_or(0);
+    if start >= archive.len() {
+        return Reply::new(416).header("content-range", &format!("bytes */{}", archive.len()));
+    }
+    let reply = match start {
+        0 => Reply::new(200),
+        _ => Reply::new(206).header(
+            "content-range",
+            &format!("bytes {start}-{}/{}", archive.len() - 1, archive.len()),
+        ),
+    }
+    .header("accept-ranges", "bytes")
+    .header("etag", &etag)
+    .header(
+        "content-disposition",
+        &format!(r#"attachment;filename="{sid} Mock - Beatmapset.osz""#),
+    );
+
+    match failure {
+        Some(Failure::Truncated) => reply
+            .header("content-length", &(archive.len() - start).to_string())
+            .body(&archive[start..start + (archive.len() - start) / 2]),
+        _ => reply.body(&archive[start..]),
+    }
+}
@@ -35,7 +35,7 @@ pub fn unzip(zip_path: &Path, path: PathBuf) -> Result<()> {
             // 文件
             if let Some(p) = outpath.parent() {
                 if !p.exists() {
-                    fs::create_dir_all(&p)?;
+                    fs::create_dir_all(p)?;
                 }
             }
             println!("释放文件{}", outpath.display());
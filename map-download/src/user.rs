@@ -1,243 +1,1060 @@
-use crate::client;
-use crate::error::OsuMapDownloadError;
-use anyhow::{Context, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
-use reqwest::header::{HeaderMap, CONTENT_TYPE, COOKIE};
+use crate::client::{check_base_url, Client};
+use crate::error::{DownloadError, OsuMapDownloadError};
+use crate::naming::{BeatmapsetMeta, MetadataProvider};
+use anyhow::Result;
+use async_trait::async_trait;
+use cookie_store::{CookieExpiration, CookieStore};
+use reqwest::header::{HeaderMap, InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{info, instrument, warn};
 
-lazy_static! {
-    static ref REG_XSRF: Regex = Regex::new(r"XSRF-TOKEN=([\w]+);").unwrap();
-    static ref REG_COOKIE: Regex = Regex::new(r"osu_session=([\w%]+);").unwrap();
+const OSU_BASE_URL: &str = "https://osu.ppy.sh";
+/// 只有登录后才能打开的页面，用来检查 session 是否有效
+const ACCOUNT_PATH: &str = "/home/account/edit";
+/// 当前保存 session 使用的格式版本，旧的 "token,session" 字符串视为版本 0
+const SESSION_FORMAT_VERSION: u64 = 1;
+const XSRF_COOKIE: &str = "XSRF-TOKEN";
+const SESSION_COOKIE: &str = "osu_session";
+
+/// [`UserSession::check`] 的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// The cookies are accepted by osu!
+    Valid,
+    /// The cookies are missing or expired, login again
+    Expired,
+    /// Can't tell, usually because of network or server errors
+    Unknown,
+}
+
+/// 登录方式，目前只有网页登录的 cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SessionAuth {
+    Cookie,
 }
 
-const HOME_PAGE_URL: &str = "https://osu.ppy.sh/home";
-static LOGIN_URL: &str = "https://osu.ppy.sh/session";
+/// `to_recoverable` 保存的 JSON 文档，不包含密码。新增字段需要 `#[serde(default)]`，
+/// 不兼容的修改需要提升 `SESSION_FORMAT_VERSION` 并在 `restore` 中迁移旧版本
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedSession {
+    version: u64,
+    username: String,
+    auth: SessionAuth,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
+    /// Unix timestamp in seconds, only for reading, the cookies have their own expiry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    cookies: Vec<cookie_store::Cookie<'static>>,
+}
 
 /// 用户信息记录,包含密码,登录后的session
 /// 包含的session信息可重用,请重用此结构
 /// 可以将session保存出来
-#[derive(Debug, Default, PartialEq)]
+#[derive(Default)]
 pub struct UserSession {
     name: String,
     password: String,
-    token: String,
-    session: String,
+    /// Every cookie set by osu!, with their domain, path and expiry
+    cookies: CookieStore,
+    /// Empty for `https://osu.ppy.sh`
+    base_url: String,
+    client: Client,
 }
 
-/// 生成请求用到的cookie字符串
-#[inline]
-fn new_cookie(xsrf: &str, cookie: &str) -> String {
-    format!("XSRF-TOKEN={xsrf}; osu_session={cookie};")
+impl fmt::Debug for UserSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserSession")
+            .field("name", &self.name)
+            .field("base_url", &self.base_url())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for UserSession {
+    fn eq(&self, other: &Self) -> bool {
+        let cookies = |user: &Self| {
+            let mut cookies: Vec<(String, String)> = user
+                .cookies
+                .iter_unexpired()
+                .map(|c| (c.name().to_string(), c.value().to_string()))
+                .collect();
+            cookies.sort();
+            cookies
+        };
+        self.name == other.name
+            && self.password == other.password
+            && self.base_url() == other.base_url()
+            && cookies(self) == cookies(other)
+    }
 }
 
 impl UserSession {
     /// 通过账号密码生产记录
     pub async fn new<T: Into<String>, U: Into<String>>(username: T, password: U) -> Result<Self> {
+        Self::new_with_client(Client::default(), username, password).await
+    }
+
+    /// 使用配置好的客户端登录，例如需要通过代理访问时
+    pub async fn new_with_client<T: Into<String>, U: Into<String>>(
+        client: Client,
+        username: T,
+        password: U,
+    ) -> Result<Self> {
         let mut session = UserSession {
             name: username.into(),
             password: password.into(),
-            token: String::new(),
-            session: String::new(),
-        };
+            ..Default::default()
+        }
+        .with_client(client);
 
         session.refresh().await?;
 
         Ok(session)
     }
 
+    /// Send the requests of this session with `client`, its base url is used if set.
+    pub fn with_client(mut self, client: Client) -> Self {
+        // already checked when the client was built
+        let base_url = client.base_url().map(str::to_string);
+        self.client = client;
+        match base_url {
+            Some(base_url) => self.move_to(base_url),
+            None => self,
+        }
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
     /// Try login into osu. Return error if any network or account error occur
+    #[instrument(name = "login", skip_all, fields(username = %self.name))]
     pub async fn refresh(&mut self) -> Result<()> {
         self.update_access().await?;
         self.login().await?;
+        info!("logged in");
         Ok(())
     }
 
+    /// Log in and download from another host, the cookies already in the session are moved
+    /// to it.
+    pub fn with_base_url<T: Into<String>>(self, base_url: T) -> Result<Self, DownloadError> {
+        let base_url = check_base_url(&base_url.into())?;
+        Ok(self.move_to(base_url))
+    }
+
+    /// Switch to a base url that is already checked.
+    fn move_to(mut self, base_url: String) -> Self {
+        let old = std::mem::take(&mut self.cookies);
+        self.base_url = base_url;
+        let url = self.url();
+        for cookie in old.iter_unexpired() {
+            let mut raw = (**cookie).clone();
+            raw.unset_domain();
+            // a cookie that doesn't fit the new host is useless anyway
+            let _ = self.cookies.insert_raw(&raw, &url);
+        }
+        self
+    }
+
+    pub(crate) fn base_url(&self) -> &str {
+        if self.base_url.is_empty() {
+            OSU_BASE_URL
+        } else {
+            &self.base_url
+        }
+    }
+
+    fn url(&self) -> Url {
+        // every base url is checked before it is set
+        Url::parse(self.base_url()).expect("base url should be valid")
+    }
+
+    /// Value of an unexpired cookie sent to osu!.
+    fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .get_request_values(&self.url())
+            .find(|(n, v)| *n == name && !v.is_empty())
+            .map(|(_, v)| v)
+    }
+
+    /// 生成请求用到的cookie字符串
+    fn cookie_header(&self) -> String {
+        self.cookies
+            .get_request_values(&self.url())
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// When the login cookie expires, `None` if it's unknown or expires with the browser
+    /// session.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let cookie = self
+            .cookies
+            .matches(&self.url())
+            .into_iter()
+            .find(|c| c.name() == SESSION_COOKIE)?;
+        match &cookie.expires {
+            CookieExpiration::AtUtc(at) => {
+                let secs = u64::try_from(at.unix_timestamp()).ok()?;
+                Some(UNIX_EPOCH + Duration::from_secs(secs))
+            }
+            CookieExpiration::SessionEnd => None,
+        }
+    }
+
+    /// 发送一个需要登录的轻量请求，检查当前的 cookie 是否还能用
+    #[instrument(level = "debug", skip_all, fields(username = %self.name), ret)]
+    pub async fn check(&self) -> SessionState {
+        // expired cookies are dropped from the jar, no need to ask the server
+        if self.cookie(XSRF_COOKIE).is_none() || self.cookie(SESSION_COOKIE).is_none() {
+            return SessionState::Expired;
+        }
+
+        let url = format!("{}{ACCOUNT_PATH}", self.base_url());
+        let mut header = HeaderMap::new();
+        match self.cookie_header().parse() {
+            Ok(cookie) => header.insert(COOKIE, cookie),
+            Err(_) => return SessionState::Expired,
+        };
+        let response = match self.client.get(&url, header).await {
+            Ok(response) => response,
+            Err(_) => return SessionState::Unknown,
+        };
+
+        match response.status() {
+            // guests are redirected away from the account page
+            reqwest::StatusCode::OK if response.url().path() == ACCOUNT_PATH => SessionState::Valid,
+            reqwest::StatusCode::OK
+            | reqwest::StatusCode::UNAUTHORIZED
+            | reqwest::StatusCode::FORBIDDEN => SessionState::Expired,
+            status if status.is_redirection() => SessionState::Expired,
+            _ => SessionState::Unknown,
+        }
+    }
+
     /// Get token and session cookie. This should be called before login cuz login needs
     /// those value.
     async fn update_access(&mut self) -> Result<()> {
         let mut header = HeaderMap::new();
-        header.insert(
-            "cookie",
-            new_cookie(&self.token, &self.session).parse().unwrap(),
-        );
-        let response = client::get(HOME_PAGE_URL, header)
-            .await
-            .with_context(|| "请求主页失败")?;
+        header.insert(COOKIE, self.cookie_header().parse()?);
+        let url = format!("{}/home", self.base_url());
+        let response = self.client.get(&url, header).await?;
 
-        match response.status() {
+        let kind = match response.status() {
             reqwest::StatusCode::OK => {
                 self.update(response.headers());
-                Ok(())
+                return Ok(());
             }
-            reqwest::StatusCode::BAD_REQUEST => {
-                Err(OsuMapDownloadError::IncorrectPasswordError.into())
-            }
-            _ => Err(OsuMapDownloadError::Unknown.into()),
-        }
+            reqwest::StatusCode::BAD_REQUEST => OsuMapDownloadError::IncorrectPasswordError,
+            _ => OsuMapDownloadError::LoginFailError,
+        };
+        Err(rejected(kind, &url, response.status()).into())
     }
 
-    pub fn new_header(&self, back_url: &str) -> HeaderMap {
+    /// Headers with the cookies and `back_url` as the referer, fails if `back_url` can't be
+    /// sent in a header.
+    pub fn new_header(&self, back_url: &str) -> Result<HeaderMap, DownloadError> {
+        let invalid = |e: InvalidHeaderValue| {
+            DownloadError::new(OsuMapDownloadError::InvalidRequestError).with_source(e)
+        };
         let mut header = HeaderMap::new();
-        header.insert(
-            COOKIE,
-            new_cookie(&self.token, &self.session).parse().unwrap(),
-        );
+        header.insert(COOKIE, self.cookie_header().parse().map_err(invalid)?);
 
-        header.insert("referer", back_url.parse().unwrap());
+        header.insert("referer", back_url.parse().map_err(invalid)?);
         header.insert(
             CONTENT_TYPE,
             reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded"),
         );
-        header
+        Ok(header)
     }
 
     /// Try login with current data
     async fn login(&mut self) -> Result<()> {
         let mut header = HeaderMap::new();
-        header.insert("referer", HOME_PAGE_URL.parse()?);
+        header.insert("referer", format!("{}/home", self.base_url()).parse()?);
         header.insert(
             reqwest::header::CONTENT_TYPE,
             reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded"),
         );
-        header.insert(
-            reqwest::header::COOKIE,
-            new_cookie(&self.token, &self.session).parse()?,
-        );
+        header.insert(reqwest::header::COOKIE, self.cookie_header().parse()?);
 
+        let token = self.cookie(XSRF_COOKIE).unwrap_or_default().to_string();
         let mut body = HashMap::new();
-        body.insert("_token".to_string(), &self.token);
+        body.insert("_token".to_string(), &token);
         body.insert("username".to_string(), &self.name);
         body.insert("password".to_string(), &self.password);
 
-        let response = client::post(LOGIN_URL, header, &body)
-            .await
-            .with_context(|| "登录请求无回复")?;
+        let url = format!("{}/session", self.base_url());
+        let response = self.client.post(&url, header, &body).await?;
 
-        match response.status() {
+        let kind = match response.status() {
             reqwest::StatusCode::OK => {
                 self.update(response.headers());
-                Ok(())
-            }
-            reqwest::StatusCode::FORBIDDEN => {
-                Err(OsuMapDownloadError::IncorrectPasswordError.into())
+                return Ok(());
             }
-            _ => {
-                println!("status:{}", response.status());
-                Err(OsuMapDownloadError::Unknown.into())
+            reqwest::StatusCode::FORBIDDEN => OsuMapDownloadError::IncorrectPasswordError,
+            status => {
+                warn!(status = status.as_u16(), "unexpected login response");
+                OsuMapDownloadError::LoginFailError
             }
-        }
+        };
+        Err(rejected(kind, &url, response.status()).into())
     }
 
-    /// 将当前的 cookie 和 token 信息转换成可供保存的字符串。
+    /// 将当前的 session 转换成可供保存的 JSON 字符串，不包含密码
     pub fn to_recoverable(&self) -> String {
-        format!("{},{}", self.token, self.session)
+        let saved = SavedSession {
+            version: SESSION_FORMAT_VERSION,
+            username: self.name.clone(),
+            auth: SessionAuth::Cookie,
+            base_url: (!self.base_url.is_empty()).then(|| self.base_url.clone()),
+            expires_at: self
+                .expires_at()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            // session cookies are kept as well, osu! may not give the login cookie an expiry
+            cookies: self.cookies.iter_unexpired().cloned().collect(),
+        };
+        serde_json::to_string_pretty(&saved).expect("session should be serializable")
     }
 
-    /// 通过保存的session数据恢复
+    /// 通过保存的session数据恢复，无法识别时返回 None，需要知道原因时使用 [`UserSession::restore`]
     pub fn from_recoverable(username: &str, data: &str) -> Option<UserSession> {
-        let parts: Vec<&str> = data.split(',').collect();
-        if parts.len() < 2 {
-            return None;
+        Self::restore(username, data).ok()
+    }
+
+    /// 通过保存的session数据恢复，旧版本的格式会被迁移。`username` 只用于不包含用户名的旧格式
+    pub fn restore(username: &str, data: &str) -> Result<UserSession, OsuMapDownloadError> {
+        let data = data.trim();
+        if !data.starts_with('{') {
+            return Self::restore_v0(username, data);
         }
-        Some(UserSession {
-            name: username.to_string(),
-            password: String::new(),
-            token: parts[0].to_string(),
-            session: parts[1].to_string(),
+
+        let value: serde_json::Value =
+            serde_json::from_str(data).map_err(|_| OsuMapDownloadError::InvalidSessionError)?;
+        match value["version"].as_u64() {
+            Some(SESSION_FORMAT_VERSION) => {}
+            Some(version) => {
+                return Err(OsuMapDownloadError::UnsupportedSessionVersion { version })
+            }
+            None => return Err(OsuMapDownloadError::InvalidSessionError),
+        }
+
+        let saved: SavedSession =
+            serde_json::from_value(value).map_err(|_| OsuMapDownloadError::InvalidSessionError)?;
+        let cookies = saved.cookies.into_iter().map(Ok::<_, ()>);
+        Ok(UserSession {
+            name: saved.username,
+            base_url: match saved.base_url {
+                Some(base_url) => check_base_url(&base_url)
+                    .map_err(|_| OsuMapDownloadError::InvalidSessionError)?,
+                None => String::new(),
+            },
+            cookies: CookieStore::from_cookies(cookies, false)
+                .map_err(|_| OsuMapDownloadError::InvalidSessionError)?,
+            ..Default::default()
         })
     }
 
+    /// The original "token,session" string.
+    fn restore_v0(username: &str, data: &str) -> Result<UserSession, OsuMapDownloadError> {
+        let mut user = UserSession {
+            name: username.to_string(),
+            ..Default::default()
+        };
+        let (token, session) = data
+            .split_once(',')
+            .ok_or(OsuMapDownloadError::InvalidSessionError)?;
+        let session = session.split(',').next().unwrap_or_default();
+        let url = user.url();
+        for cookie in [
+            format!("{XSRF_COOKIE}={token}"),
+            format!("{SESSION_COOKIE}={session}"),
+        ] {
+            user.cookies
+                .parse(&cookie, &url)
+                .map_err(|_| OsuMapDownloadError::InvalidSessionError)?;
+        }
+        Ok(user)
+    }
+
+    /// 给恢复出来的 session 设置密码，cookie 过期时 `refresh` 才能重新登录
+    pub fn set_password<T: Into<String>>(&mut self, password: T) {
+        self.password = password.into();
+    }
+
+    /// Sessions restored by `from_recoverable` have no password and can't refresh.
+    pub(crate) fn has_password(&self) -> bool {
+        !self.password.is_empty()
+    }
+
     /// Get immutable reference to inner name
     pub fn username(&self) -> &str {
         &self.name
     }
 
-    // 更新 token 和 session。如果传入的 HeaderMap 没有满足更新的值，旧的值会保留
+    /// 把响应中 set-cookie 的内容存进 cookie jar，过期的 cookie 会被删除
     pub fn update(&mut self, header_map: &HeaderMap) {
-        let all_headers = header_map.get_all("set-cookie");
-        let mut xsrf_change = false;
-        let mut cookie_change = false;
-        for header in all_headers {
-            // early return to save regexp match time
-            if xsrf_change && cookie_change {
-                return;
+        let url = self.url();
+        for header in header_map.get_all("set-cookie") {
+            if let Ok(value) = header.to_str() {
+                // cookies that don't belong to osu! are rejected by the jar
+                let _ = self.cookies.parse(value, &url);
             }
+        }
+    }
+}
 
-            let str = header.to_str();
-            if str.is_err() {
-                continue;
-            }
-            // it is safe to unwrap now
-            let str = str.unwrap();
-
-            // FIXME: 这里的元组只是一个暂时的解决地狱 if 嵌套的方案，等 Rust 1.63 版本发布之后，
-            // 可以使用 if let chain 来重写这个条件判断
-            // 链接：https://github.com/rust-lang/rust/pull/94927
-            if let (true, Some(xsrf)) = (!xsrf_change, REG_XSRF.captures(str)) {
-                let old_token = &self.token;
-                self.token = xsrf
-                    .get(1)
-                    .map_or_else(|| old_token.clone(), |v| v.as_str().to_string());
-                xsrf_change = true;
-
-                continue;
-            }
+/// osu! API v2 的 access token，提前这么久刷新，避免请求途中过期
+const TOKEN_RENEW_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+/// POST the form to `{base_url}/oauth/token`, shared by all the OAuth grants.
+#[instrument(level = "debug", skip_all)]
+async fn request_token(
+    client: &Client,
+    base_url: &str,
+    params: &[(&str, &str)],
+) -> Result<TokenResponse> {
+    let values: Vec<(String, String)> = params
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let form: HashMap<String, &String> = values.iter().map(|(k, v)| (k.clone(), v)).collect();
+
+    let url = format!("{base_url}/oauth/token");
+    let response = client.post(&url, HeaderMap::new(), &form).await?;
+
+    let status = response.status();
+    let kind = match status {
+        reqwest::StatusCode::OK => {
+            return response.json().await.map_err(|e| {
+                rejected(OsuMapDownloadError::InvalidResponseError, &url, status)
+                    .with_source(e)
+                    .into()
+            })
+        }
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::BAD_REQUEST => {
+            // 400 is also used for a malformed request or a wrong redirect_uri
+            let body: TokenError = response.json().await.unwrap_or_default();
+            warn!(status = status.as_u16(), error = %body.error, "token request rejected");
+            let kind = match body.error.as_str() {
+                "invalid_client" | "invalid_grant" => OsuMapDownloadError::IncorrectPasswordError,
+                _ => OsuMapDownloadError::LoginFailError,
+            };
+            let reason = match body.error_description {
+                Some(description) => format!("{}: {description}", body.error),
+                None => body.error,
+            };
+            return Err(rejected(kind, &url, status).with_source(reason).into());
+        }
+        status => {
+            warn!(status = status.as_u16(), "unexpected token response");
+            OsuMapDownloadError::LoginFailError
+        }
+    };
+    Err(rejected(kind, &url, status).into())
+}
+
+/// The body of a rejected token request, see RFC 6749 section 5.2.
+#[derive(Debug, Default, Deserialize)]
+struct TokenError {
+    #[serde(default)]
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The error of a login or token request answered with `status`.
+fn rejected(kind: OsuMapDownloadError, url: &str, status: reqwest::StatusCode) -> DownloadError {
+    DownloadError::new(kind)
+        .with_url(url)
+        .with_status(status.as_u16())
+}
+
+#[derive(Debug, Clone)]
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+/// osu! API v2 的 OAuth client credentials 认证，只需要 client id 和 secret，不需要用户密码
+/// 获取到的 token 会被缓存，快过期时自动重新获取
+#[derive(Debug)]
+pub struct ClientCredentials {
+    base_url: String,
+    client: Client,
+    client_id: String,
+    client_secret: String,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl ClientCredentials {
+    pub fn new<T: Into<String>, U: Into<String>>(client_id: T, client_secret: U) -> Self {
+        ClientCredentials {
+            base_url: OSU_BASE_URL.to_string(),
+            client: Client::default(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            token: Mutex::new(None),
+        }
+    }
 
-            if let (true, Some(cookie_match)) = (!cookie_change, REG_COOKIE.captures(str)) {
-                let old_session = &self.session;
-                self.session = cookie_match
-                    .get(1)
-                    .map_or_else(|| old_session.clone(), |v| v.as_str().to_string());
-                cookie_change = true;
+    /// Request the tokens and call API v2 on another host.
+    pub fn with_base_url<T: Into<String>>(mut self, base_url: T) -> Result<Self, DownloadError> {
+        self.base_url = check_base_url(&base_url.into())?;
+        Ok(self)
+    }
+
+    /// Send the requests with `client`, its base url is used if set.
+    pub fn with_client(mut self, client: Client) -> Self {
+        if let Some(base_url) = client.base_url() {
+            self.base_url = base_url.to_string();
+        }
+        self.client = client;
+        self
+    }
+
+    /// Return a valid access token, request a new one if there is none or it expires soon.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(t) = token.as_ref() {
+            if t.expires_at > Instant::now() + TOKEN_RENEW_MARGIN {
+                return Ok(t.value.clone());
             }
         }
+
+        let fresh = self.request_token().await?;
+        let value = fresh.value.clone();
+        *token = Some(fresh);
+        Ok(value)
+    }
+
+    async fn request_token(&self) -> Result<AccessToken> {
+        let token = request_token(
+            &self.client,
+            &self.base_url,
+            &[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("grant_type", "client_credentials"),
+                ("scope", "public"),
+            ],
+        )
+        .await?;
+        Ok(AccessToken {
+            value: token.access_token,
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+        })
+    }
+
+    /// Headers with `Authorization: Bearer <token>` for calling API v2.
+    pub async fn auth_header(&self) -> Result<HeaderMap> {
+        Ok(bearer_header(&self.access_token().await?)?)
+    }
+}
+
+/// Headers with `Authorization: Bearer <token>` for calling API v2.
+fn bearer_header(token: &str) -> Result<HeaderMap, DownloadError> {
+    let value = format!("Bearer {token}")
+        .parse()
+        .map_err(|e: InvalidHeaderValue| {
+            DownloadError::new(OsuMapDownloadError::InvalidResponseError).with_source(e)
+        })?;
+    let mut header = HeaderMap::new();
+    header.insert(AUTHORIZATION, value);
+    Ok(header)
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiBeatmapset {
+    artist: String,
+    title: String,
+    creator: String,
+}
+
+#[async_trait]
+impl MetadataProvider for ClientCredentials {
+    async fn beatmapset(&self, sid: &str) -> Result<BeatmapsetMeta> {
+        let url = format!("{}/api/v2/beatmapsets/{sid}", self.base_url);
+        let response = self.client.get(&url, self.auth_header().await?).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(rejected(
+                OsuMapDownloadError::NotFoundMapError,
+                &url,
+                response.status(),
+            )
+            .into());
+        }
+        let status = response.status();
+        let set: ApiBeatmapset = response.error_for_status()?.json().await.map_err(|e| {
+            rejected(OsuMapDownloadError::InvalidResponseError, &url, status).with_source(e)
+        })?;
+        Ok(BeatmapsetMeta {
+            artist: set.artist,
+            title: set.title,
+            creator: set.creator,
+        })
+    }
+}
+
+/// 可以保存下来的 OAuth 用户 token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+impl OAuthToken {
+    fn from_response(token: TokenResponse, old_refresh: Option<&str>) -> Result<Self> {
+        let refresh_token = token
+            .refresh_token
+            .or_else(|| old_refresh.map(str::to_string))
+            .ok_or_else(|| DownloadError::new(OsuMapDownloadError::InvalidResponseError))?;
+        Ok(OAuthToken {
+            access_token: token.access_token,
+            refresh_token,
+            expires_at: unix_now() + token.expires_in,
+        })
+    }
+
+    fn expires_soon(&self) -> bool {
+        self.expires_at <= unix_now() + TOKEN_RENEW_MARGIN.as_secs()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// osu! OAuth 的 authorization code 登录流程，用户在浏览器中授权，不需要输入密码
+#[derive(Debug, Clone)]
+pub struct AuthorizationCode {
+    base_url: String,
+    client: Client,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+}
+
+impl AuthorizationCode {
+    /// `redirect_uri` must be the same as the one registered for the OAuth application.
+    pub fn new<T, U, V>(client_id: T, client_secret: U, redirect_uri: V) -> Self
+    where
+        T: Into<String>,
+        U: Into<String>,
+        V: Into<String>,
+    {
+        AuthorizationCode {
+            base_url: OSU_BASE_URL.to_string(),
+            client: Client::default(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            redirect_uri: redirect_uri.into(),
+        }
+    }
+
+    /// Authorize and request the tokens on another host.
+    pub fn with_base_url<T: Into<String>>(mut self, base_url: T) -> Result<Self, DownloadError> {
+        self.base_url = check_base_url(&base_url.into())?;
+        Ok(self)
+    }
+
+    /// Send the requests with `client`, its base url is used if set.
+    pub fn with_client(mut self, client: Client) -> Self {
+        if let Some(base_url) = client.base_url() {
+            self.base_url = base_url.to_string();
+        }
+        self.client = client;
+        self
+    }
+
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    /// The page the user should open in a browser. `state` is sent back with the code and
+    /// must be checked by the caller.
+    pub fn authorize_url(&self, state: &str) -> Result<String> {
+        let url = Url::parse_with_params(
+            &format!("{}/oauth/authorize", self.base_url),
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("response_type", "code"),
+                ("scope", "public identify"),
+                ("state", state),
+            ],
+        )?;
+        Ok(url.to_string())
+    }
+
+    /// Exchange the code from the redirect for access and refresh tokens.
+    pub async fn exchange(self, code: &str) -> Result<OAuthSession> {
+        let token = request_token(
+            &self.client,
+            &self.base_url,
+            &[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_uri),
+            ],
+        )
+        .await?;
+        let token = OAuthToken::from_response(token, None)?;
+        Ok(OAuthSession::from_token(self, token))
+    }
+}
+
+/// 通过 authorization code 登录得到的会话，access token 快过期时自动用 refresh token 刷新
+#[derive(Debug)]
+pub struct OAuthSession {
+    app: AuthorizationCode,
+    token: Mutex<OAuthToken>,
+}
+
+impl OAuthSession {
+    /// Restore a session from a saved token.
+    pub fn from_token(app: AuthorizationCode, token: OAuthToken) -> Self {
+        OAuthSession {
+            app,
+            token: Mutex::new(token),
+        }
+    }
+
+    pub(crate) fn base_url(&self) -> &str {
+        &self.app.base_url
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        &self.app.client
+    }
+
+    /// The current token, save it to restore the session later.
+    pub async fn token(&self) -> OAuthToken {
+        self.token.lock().await.clone()
+    }
+
+    /// Return a valid access token, refresh it first if it expires soon.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if token.expires_soon() {
+            *token = self.request_refresh(&token.refresh_token).await?;
+        }
+        Ok(token.access_token.clone())
+    }
+
+    /// Get a new access token with the refresh token.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut token = self.token.lock().await;
+        *token = self.request_refresh(&token.refresh_token).await?;
+        Ok(())
+    }
+
+    async fn request_refresh(&self, refresh_token: &str) -> Result<OAuthToken> {
+        let app = &self.app;
+        let token = request_token(
+            &app.client,
+            &app.base_url,
+            &[
+                ("client_id", &app.client_id),
+                ("client_secret", &app.client_secret),
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ],
+        )
+        .await?;
+        OAuthToken::from_response(token, Some(refresh_token))
+    }
+
+    /// Headers with `Authorization: Bearer <token>` for calling API v2.
+    pub async fn auth_header(&self) -> Result<HeaderMap> {
+        Ok(bearer_header(&self.access_token().await?)?)
     }
 }
 
 #[test]
 fn test_user_from_recoverable() {
-    // test valid
-    let user = UserSession::from_recoverable("abc", "def,123");
-    assert_eq!(
-        user,
-        Some(UserSession {
-            name: String::from("abc"),
-            password: String::new(),
-            token: String::from("def"),
-            session: String::from("123"),
-        })
-    );
-    let user = user.unwrap();
-    assert_eq!(user.to_recoverable(), "def,123");
+    // the old "token,session" format
+    let user = UserSession::from_recoverable("abc", "def,123").unwrap();
+    assert_eq!(user.cookie(XSRF_COOKIE), Some("def"));
+    assert_eq!(user.cookie(SESSION_COOKIE), Some("123"));
+    assert_eq!(user.expires_at(), None);
+
+    let saved = user.to_recoverable();
+    let value: serde_json::Value = serde_json::from_str(&saved).unwrap();
+    assert_eq!(value["version"], 1);
+    assert_eq!(value["username"], "abc");
+    assert_eq!(value["auth"], "cookie");
+    // the username in the document wins
+    let restored = UserSession::restore("other", &saved).unwrap();
+    assert_eq!(restored, user);
 
     // test invalid
     let user = UserSession::from_recoverable("abc", "def");
     assert_eq!(user, None);
+    assert_eq!(
+        UserSession::restore("abc", r#"{"version":99,"username":"abc"}"#),
+        Err(OsuMapDownloadError::UnsupportedSessionVersion { version: 99 })
+    );
+    assert_eq!(
+        UserSession::restore("abc", r#"{"version":1}"#),
+        Err(OsuMapDownloadError::InvalidSessionError)
+    );
+    let saved = r#"{"version":1,"username":"abc","auth":"cookie","base_url":"osu","cookies":[]}"#;
+    assert_eq!(
+        UserSession::restore("abc", saved),
+        Err(OsuMapDownloadError::InvalidSessionError)
+    );
+    let saved = saved.replace(r#""osu""#, r#""http://osu.test/""#);
+    assert_eq!(
+        UserSession::restore("abc", &saved).unwrap().base_url(),
+        "http://osu.test"
+    );
+
+    // a bad base url or referer is an error instead of a panic
+    let user = UserSession::from_recoverable("abc", "def,123").unwrap();
+    assert!(user.new_header("https://osu.ppy.sh/beatmapsets/1").is_ok());
+    assert!(user
+        .new_header("https://osu.ppy.sh/beatmapsets/1\n")
+        .is_err());
+    let error = user.with_base_url("osu.ppy.sh").unwrap_err();
+    assert_eq!(error, OsuMapDownloadError::InvalidRequestError);
+    assert!(ClientCredentials::new("id", "secret")
+        .with_base_url("ftp://osu.ppy.sh")
+        .is_err());
+    assert!(AuthorizationCode::new("id", "secret", "http://127.0.0.1/")
+        .with_base_url("/oauth")
+        .is_err());
 }
 
 #[test]
 fn test_user_update_header() {
+    use reqwest::header::HeaderValue;
+
     let mut user = UserSession::from_recoverable("foo", "bar,123").unwrap();
     let mut headers = HeaderMap::new();
-    use reqwest::header::HeaderValue;
+    for cookie in [
+        "XSRF-TOKEN=abc%3D%3D; expires=Wed, 21 Oct 2099 07:28:00 GMT; path=/",
+        "osu_session=ghijklm78901; expires=Wed, 21 Oct 2099 07:28:00 GMT; path=/; httponly",
+        "locale=en; Max-Age=3600; domain=.ppy.sh",
+        "tracker=1; domain=example.com",
+    ] {
+        headers.append("set-cookie", HeaderValue::from_str(cookie).unwrap());
+    }
+
+    user.update(&headers);
+    assert_eq!(user.cookie(XSRF_COOKIE), Some("abc%3D%3D"));
+    assert_eq!(user.cookie(SESSION_COOKIE), Some("ghijklm78901"));
+    assert_eq!(user.cookie("locale"), Some("en"));
+    assert_eq!(user.cookie("tracker"), None);
+    assert_eq!(
+        user.expires_at(),
+        Some(UNIX_EPOCH + Duration::from_secs(4096250880))
+    );
+
+    // the server removes the cookie by expiring it
+    let mut headers = HeaderMap::new();
     headers.insert(
         "set-cookie",
-        HeaderValue::from_str("XSRF-TOKEN=abcdef12345;").unwrap(),
+        HeaderValue::from_static("osu_session=; Max-Age=0; path=/"),
     );
-    headers.append(
-        "set-cookie",
-        HeaderValue::from_str("osu_session=ghijklm78901;").unwrap(),
+    user.update(&headers);
+    assert_eq!(user.cookie(SESSION_COOKIE), None);
+}
+
+#[tokio::test]
+async fn test_session_check() {
+    use crate::testing::{Reply, StandIn};
+
+    let server = StandIn::start(|req| match req.header("cookie") {
+        _ if req.path == "/home" => Reply::new(200),
+        Some(cookie) if cookie.contains("osu_session=good") => Reply::new(200),
+        Some(cookie) if cookie.contains("osu_session=down") => Reply::new(503),
+        _ => Reply::new(302).header("location", "/home"),
+    })
+    .await;
+
+    let check = |data: &str| {
+        UserSession::from_recoverable("foo", data)
+            .unwrap()
+            .with_base_url(server.url(""))
+            .unwrap()
+    };
+    assert_eq!(check("xsrf,good").check().await, SessionState::Valid);
+    assert_eq!(check("xsrf,old").check().await, SessionState::Expired);
+    assert_eq!(check("xsrf,down").check().await, SessionState::Unknown);
+    assert_eq!(check(",").check().await, SessionState::Expired);
+}
+
+#[tokio::test]
+async fn test_login() {
+    use crate::testing::MockOsu;
+
+    let osu = MockOsu::start().await.with_account("foo", "bar");
+    let err = UserSession::new_with_client(osu.client(), "foo", "wrong")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<DownloadError>().map(DownloadError::kind),
+        Some(&OsuMapDownloadError::IncorrectPasswordError)
     );
 
-    user.update(&headers);
+    let mut user = UserSession::new_with_client(osu.client(), "foo", "bar")
+        .await
+        .unwrap();
+    assert_eq!(user.check().await, SessionState::Valid);
+    let login = osu.requests().into_iter().find(|r| r.path == "/session");
+    assert!(String::from_utf8(login.unwrap().body)
+        .unwrap()
+        .contains("username=foo"));
+
+    osu.expire_sessions();
+    assert_eq!(user.check().await, SessionState::Expired);
+    user.refresh().await.unwrap();
+    assert_eq!(user.check().await, SessionState::Valid);
+    assert_eq!(osu.logins(), 2);
+}
+
+#[tokio::test]
+async fn test_client_credentials() {
+    use crate::testing::{Reply, StandIn};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let issued = AtomicUsize::new(0);
+    let server = StandIn::start(move |req| match req.path.as_str() {
+        "/oauth/token" => {
+            let n = issued.fetch_add(1, Ordering::SeqCst);
+            // the first token expires at once, the second one lasts a day
+            let expires_in = if n == 0 { 0 } else { 86400 };
+            Reply::new(200)
+                .header("content-type", "application/json")
+                .body(format!(
+                    r#"{{"token_type":"Bearer","expires_in":{expires_in},"access_token":"token{n}"}}"#
+                ))
+        }
+        "/api/v2/beatmapsets/1" if req.header("authorization") == Some("Bearer token1") => {
+            Reply::new(200)
+                .header("content-type", "application/json")
+                .body(r#"{"id":1,"artist":"Art","title":"Title","creator":"peppy"}"#)
+        }
+        _ => Reply::new(401),
+    })
+    .await;
+
+    let credentials = ClientCredentials::new("id", "secret")
+        .with_base_url(server.url(""))
+        .unwrap();
+    assert_eq!(credentials.access_token().await.unwrap(), "token0");
+    assert_eq!(credentials.access_token().await.unwrap(), "token1");
+    assert_eq!(credentials.access_token().await.unwrap(), "token1");
+
+    let meta = credentials.beatmapset("1").await.unwrap();
+    assert_eq!(meta.creator, "peppy");
+
+    let requests = server.requests();
     assert_eq!(
-        user,
-        UserSession {
-            name: String::from("foo"),
-            password: String::new(),
-            session: String::from("ghijklm78901"),
-            token: String::from("abcdef12345"),
-        }
-    )
+        requests.iter().filter(|r| r.path == "/oauth/token").count(),
+        2
+    );
+    let form = String::from_utf8_lossy(&requests[0].body).to_string();
+    assert!(form.contains("grant_type=client_credentials"));
+    assert!(form.contains("client_secret=secret"));
+}
+
+#[tokio::test]
+async fn test_token_errors() {
+    use crate::testing::{Reply, StandIn};
+
+    let server = StandIn::start(|req| {
+        let error = match String::from_utf8_lossy(&req.body) {
+            body if body.contains("client_secret=wrong") => "invalid_client",
+            body if body.contains("code=used") => "invalid_grant",
+            _ => "invalid_request",
+        };
+        Reply::new(400)
+            .header("content-type", "application/json")
+            .body(format!(
+                r#"{{"error":"{error}","error_description":"rejected","message":"rejected"}}"#
+            ))
+    })
+    .await;
+    let kind = |params: &'static [(&'static str, &'static str)]| {
+        let url = server.url("");
+        async move {
+            let err = request_token(&Client::default(), &url, params)
+                .await
+                .unwrap_err();
+            err.downcast_ref::<DownloadError>().unwrap().kind().clone()
+        }
+    };
+
+    assert_eq!(
+        kind(&[("client_secret", "wrong")]).await,
+        OsuMapDownloadError::IncorrectPasswordError
+    );
+    assert_eq!(
+        kind(&[("code", "used")]).await,
+        OsuMapDownloadError::IncorrectPasswordError
+    );
+    assert_eq!(
+        kind(&[("redirect_uri", "http://elsewhere")]).await,
+        OsuMapDownloadError::LoginFailError
+    );
+}
+
+#[tokio::test]
+async fn test_authorization_code() {
+    use crate::testing::{Reply, StandIn};
+
+    let server = StandIn::start(|req| {
+        let form = String::from_utf8_lossy(&req.body).to_string();
+        let body = if form.contains("grant_type=authorization_code") && form.contains("code=abc") {
+            r#"{"expires_in":0,"access_token":"a1","refresh_token":"r1"}"#
+        } else if form.contains("grant_type=refresh_token") && form.contains("refresh_token=r1") {
+            r#"{"expires_in":86400,"access_token":"a2"}"#
+        } else {
+            return Reply::new(400);
+        };
+        Reply::new(200)
+            .header("content-type", "application/json")
+            .body(body)
+    })
+    .await;
+
+    let app = AuthorizationCode::new("id", "secret", "http://127.0.0.1:7270/callback")
+        .with_base_url(server.url(""))
+        .unwrap();
+    let url = app.authorize_url("xyz").unwrap();
+    assert!(url.contains("redirect_uri=http%3A%2F%2F127.0.0.1%3A7270%2Fcallback"));
+    assert!(url.contains("state=xyz"));
+
+    let session = app.exchange("abc").await.unwrap();
+    // the first token is already expired, so it is refreshed, and the refresh token is kept
+    assert_eq!(session.access_token().await.unwrap(), "a2");
+    assert_eq!(session.token().await.refresh_token, "r1");
+    assert_eq!(session.access_token().await.unwrap(), "a2");
+    assert_eq!(server.requests().len(), 2);
 }
@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::OsuMapDownloadError;
+
+/// 检查下载好的文件是否是 osu 能导入的谱面包：一个至少包含一个 .osu 文件的 zip 压缩包
+pub(crate) fn verify_osz(path: &Path) -> Result<(), OsuMapDownloadError> {
+    let invalid = |reason: String| OsuMapDownloadError::InvalidArchiveError { reason };
+
+    let file = File::open(path).map_err(|e| invalid(e.to_string()))?;
+    let zip = zip::ZipArchive::new(file).map_err(|e| invalid(e.to_string()))?;
+    if zip
+        .file_names()
+        .any(|name| name.to_lowercase().ends_with(".osu"))
+    {
+        Ok(())
+    } else {
+        Err(OsuMapDownloadError::EmptyArchiveError)
+    }
+}
+
+#[test]
+fn test_verify_osz() {
+    use crate::testing::{osz, TempDir};
+
+    let dir = TempDir::new("verify-osz");
+
+    let valid = dir.join("valid.osz");
+    std::fs::write(
+        &valid,
+        osz(&["audio.mp3", "Artist - Title (Mapper) [Hard].osu"]),
+    )
+    .unwrap();
+    assert_eq!(verify_osz(&valid), Ok(()));
+
+    let no_map = dir.join("no_map.osz");
+    std::fs::write(&no_map, osz(&["audio.mp3"])).unwrap();
+    assert_eq!(
+        verify_osz(&no_map),
+        Err(OsuMapDownloadError::EmptyArchiveError)
+    );
+
+    let html = dir.join("html.osz");
+    std::fs::write(&html, "<html>Too Many Requests</html>").unwrap();
+    assert!(matches!(
+        verify_osz(&html),
+        Err(OsuMapDownloadError::InvalidArchiveError { .. })
+    ));
+}
@@ -0,0 +1,364 @@
+use std::fs;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+#[cfg(feature = "pswd-store")]
+use crate::pswd_store;
+
+/// 默认的获取顺序，没有启用 pswd-store 时跳过 keyring
+pub const DEFAULT_ORDER: &[&str] = &["env", "file", "keyring", "prompt"];
+
+/// 用户名和密码的来源
+pub trait CredentialProvider {
+    /// Name used in the `credentials` config and messages.
+    fn name(&self) -> &str;
+
+    /// The username to use when none is configured.
+    fn username(&self) -> Result<Option<String>>;
+
+    /// The password of the user, `None` if this provider doesn't know it.
+    fn password(&self, username: &str) -> Result<Option<String>>;
+
+    /// Asks the user, should not be used when nobody is watching.
+    fn is_interactive(&self) -> bool {
+        false
+    }
+
+    /// Remember a password that worked, only the keyring does this.
+    fn store(&self, _username: &str, _password: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove the stored password.
+    fn forget(&self, _username: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 从环境变量 OSU_USERNAME 和 OSU_PASSWORD 读取
+pub struct EnvProvider {
+    var: fn(&str) -> Option<String>,
+}
+
+impl EnvProvider {
+    /// Read the variables through `var`, which is `std::env::var` outside of tests.
+    pub fn new(var: fn(&str) -> Option<String>) -> Self {
+        EnvProvider { var }
+    }
+}
+
+impl CredentialProvider for EnvProvider {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn username(&self) -> Result<Option<String>> {
+        Ok((self.var)("OSU_USERNAME"))
+    }
+
+    fn password(&self, username: &str) -> Result<Option<String>> {
+        // the password belongs to OSU_USERNAME if it is set
+        match (self.var)("OSU_USERNAME") {
+            Some(name) if name != username => Ok(None),
+            _ => Ok((self.var)("OSU_PASSWORD")),
+        }
+    }
+}
+
+/// 从文件读取，每行一个 `用户名:密码`，其他用户可读写时拒绝使用
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new(path: PathBuf) -> Self {
+        FileProvider { path }
+    }
+
+    /// Lines of `username:password`, empty if the file doesn't exist.
+    fn entries(&self) -> Result<Vec<(String, String)>> {
+        if !self.path.is_file() {
+            return Ok(vec![]);
+        }
+        self.check_permission()?;
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("无法读取密码文件 {}", self.path.display()))?;
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, password)| (name.trim().to_string(), password.to_string()))
+            .collect())
+    }
+
+    #[cfg(unix)]
+    fn check_permission(&self) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(&self.path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            bail!(
+                "密码文件 {} 的权限过于宽松 ({:o})，请执行 chmod 600",
+                self.path.display(),
+                mode & 0o777
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_permission(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl CredentialProvider for FileProvider {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn username(&self) -> Result<Option<String>> {
+        Ok(self.entries()?.into_iter().next().map(|(name, _)| name))
+    }
+
+    fn password(&self, username: &str) -> Result<Option<String>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|(name, _)| name == username)
+            .map(|(_, password)| password))
+    }
+}
+
+/// 系统密码管理器，登录成功后保存密码
+#[cfg(feature = "pswd-store")]
+pub struct KeyringProvider;
+
+#[cfg(feature = "pswd-store")]
+impl CredentialProvider for KeyringProvider {
+    fn name(&self) -> &str {
+        "keyring"
+    }
+
+    fn username(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn password(&self, username: &str) -> Result<Option<String>> {
+        Ok(pswd_store::get(&pswd_store::entry(username), username).ok())
+    }
+
+    fn store(&self, username: &str, password: &str) -> Result<()> {
+        pswd_store::set(&pswd_store::entry(username), username, password)
+    }
+
+    fn forget(&self, username: &str) -> Result<()> {
+        pswd_store::delete(&pswd_store::entry(username), username)
+    }
+}
+
+/// 在命令行中询问，标准输入不是终端时跳过
+pub struct PromptProvider;
+
+impl CredentialProvider for PromptProvider {
+    fn name(&self) -> &str {
+        "prompt"
+    }
+
+    fn username(&self) -> Result<Option<String>> {
+        if !std::io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        println!("没有用户名，请输入你的 osu 用户名: ");
+        let mut buffer = String::new();
+        std::io::stdin()
+            .read_line(&mut buffer)
+            .with_context(|| "非法的用户名输入，请重试")?;
+        let name = buffer.trim().to_string();
+        Ok((!name.is_empty()).then_some(name))
+    }
+
+    fn password(&self, username: &str) -> Result<Option<String>> {
+        if !std::io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        let password = rpassword::prompt_password(format!("请输入 {username} 的密码: "))?;
+        Ok(Some(password))
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/// 按顺序询问各个来源，第一个给出结果的来源生效
+pub struct CredentialChain {
+    providers: Vec<Box<dyn CredentialProvider>>,
+}
+
+impl CredentialChain {
+    /// Build the chain from provider names such as `["env", "file", "prompt"]`.
+    pub fn new<T: AsRef<str>>(order: &[T], file: PathBuf) -> Result<Self> {
+        let mut providers: Vec<Box<dyn CredentialProvider>> = vec![];
+        for name in order {
+            match name.as_ref() {
+                "env" => providers.push(Box::new(EnvProvider::new(|key| std::env::var(key).ok()))),
+                "file" => providers.push(Box::new(FileProvider::new(file.clone()))),
+                #[cfg(feature = "pswd-store")]
+                "keyring" => providers.push(Box::new(KeyringProvider)),
+                #[cfg(not(feature = "pswd-store"))]
+                "keyring" => continue,
+                "prompt" => providers.push(Box::new(PromptProvider)),
+                other => bail!("未知的密码来源：{other}，可选 env、file、keyring、prompt"),
+            }
+        }
+        Ok(CredentialChain { providers })
+    }
+
+    pub fn username(&self) -> Result<String> {
+        for provider in &self.providers {
+            if let Some(name) = provider.username()? {
+                return Ok(name);
+            }
+        }
+        Err(anyhow!("没有用户名，请使用 -u 指定或设置 OSU_USERNAME"))
+    }
+
+    /// Passwords of the user from every provider in order, the interactive ones are asked
+    /// only when `interactive` is true. The flag tells if the password was typed in.
+    pub fn passwords<'a>(
+        &'a self,
+        username: &'a str,
+        interactive: bool,
+    ) -> impl Iterator<Item = Result<(String, bool)>> + 'a {
+        self.providers
+            .iter()
+            .filter(move |p| interactive || !p.is_interactive())
+            .filter_map(move |p| {
+                p.password(username)
+                    .with_context(|| format!("从 {} 获取密码失败", p.name()))
+                    .map(|password| password.map(|password| (password, p.is_interactive())))
+                    .transpose()
+            })
+    }
+
+    /// Remember the password in every provider that can store it.
+    pub fn store(&self, username: &str, password: &str) {
+        for provider in &self.providers {
+            // the login itself succeeded, failing to save the password is not fatal
+            if let Err(e) = provider.store(username, password) {
+                println!("无法保存密码：{e}");
+            }
+        }
+    }
+
+    pub fn forget(&self, username: &str) -> Result<()> {
+        for provider in &self.providers {
+            provider.forget(username)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_env_provider() {
+    let provider = EnvProvider::new(|key| match key {
+        "OSU_USERNAME" => Some("foo".to_string()),
+        "OSU_PASSWORD" => Some("bar".to_string()),
+        _ => None,
+    });
+    assert_eq!(provider.username().unwrap().as_deref(), Some("foo"));
+    assert_eq!(provider.password("foo").unwrap().as_deref(), Some("bar"));
+    // the password belongs to OSU_USERNAME only
+    assert_eq!(provider.password("other").unwrap(), None);
+
+    let provider = EnvProvider::new(|key| (key == "OSU_PASSWORD").then(|| "bar".to_string()));
+    assert_eq!(provider.username().unwrap(), None);
+    assert_eq!(provider.password("other").unwrap().as_deref(), Some("bar"));
+}
+
+#[test]
+fn test_file_provider() {
+    use osurs::map_download::testing::TempDir;
+
+    let dir = TempDir::new("credential-file");
+    let path = dir.join("credentials");
+    let provider = FileProvider::new(path.clone());
+    assert_eq!(provider.username().unwrap(), None);
+
+    fs::write(&path, "# comment\n\nfoo:bar:baz\n bob :secret\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(provider.password("foo").is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    assert_eq!(provider.username().unwrap().as_deref(), Some("foo"));
+    assert_eq!(
+        provider.password("foo").unwrap().as_deref(),
+        Some("bar:baz")
+    );
+    assert_eq!(provider.password("bob").unwrap().as_deref(), Some("secret"));
+    assert_eq!(provider.password("alice").unwrap(), None);
+}
+
+#[test]
+fn test_credential_chain() {
+    struct Fixed(&'static str, Result<Option<&'static str>, ()>, bool);
+
+    impl CredentialProvider for Fixed {
+        fn name(&self) -> &str {
+            self.0
+        }
+        fn username(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn password(&self, _username: &str) -> Result<Option<String>> {
+            match self.1 {
+                Ok(password) => Ok(password.map(str::to_string)),
+                Err(()) => bail!("locked"),
+            }
+        }
+        fn is_interactive(&self) -> bool {
+            self.2
+        }
+    }
+
+    let chain =
+        CredentialChain::new(&["prompt", "file", "keyring", "env"], PathBuf::new()).unwrap();
+    let names: Vec<&str> = chain.providers.iter().map(|p| p.name()).collect();
+    #[cfg(feature = "pswd-store")]
+    assert_eq!(names, ["prompt", "file", "keyring", "env"]);
+    #[cfg(not(feature = "pswd-store"))]
+    assert_eq!(names, ["prompt", "file", "env"]);
+    assert!(CredentialChain::new(&["env", "netrc"], PathBuf::new()).is_err());
+
+    let chain = CredentialChain {
+        providers: vec![
+            Box::new(Fixed("keyring", Err(()), false)),
+            Box::new(Fixed("env", Ok(None), false)),
+            Box::new(Fixed("file", Ok(Some("saved")), false)),
+            Box::new(Fixed("prompt", Ok(Some("typed")), true)),
+        ],
+    };
+    let passwords: Vec<_> = chain
+        .passwords("foo", true)
+        .map(|p| p.map_err(|e| e.to_string()))
+        .collect();
+    assert_eq!(
+        passwords,
+        [
+            Err("从 keyring 获取密码失败".to_string()),
+            Ok(("saved".to_string(), false)),
+            Ok(("typed".to_string(), true)),
+        ]
+    );
+    // the prompt is left out when nobody can answer it
+    assert_eq!(chain.passwords("foo", false).count(), 2);
+}
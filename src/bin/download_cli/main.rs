@@ -1,16 +1,26 @@
+mod credential;
+mod oauth;
+mod progress;
 /// Enable pswd-store features to store user password.
 #[cfg(feature = "pswd-store")]
 mod pswd_store;
 
 use std::fs;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 use directories::BaseDirs;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
 
+use credential::CredentialChain;
 use osurs::map_download::prelude::*;
+use progress::BarProgress;
 
 #[derive(Debug, Parser)]
 #[clap(name = "osu beatmap downloader")]
@@ -20,16 +30,122 @@ use osurs::map_download::prelude::*;
 struct Cli {
     #[clap(help = "输入下载谱面的sid，可以用空格隔开输入多个")]
     sid: Vec<String>,
-    #[clap(short, help = "进入登录模式，只更新 cookie 信息，不下载歌曲")]
+    #[clap(
+        short,
+        help = "进入登录模式，只更新 cookie 信息，不下载歌曲。登录新的用户名会把它加入账号列表"
+    )]
     login: bool,
+    #[clap(
+        long,
+        help = "配合 -l 使用，在浏览器中授权登录 (OAuth)，需要在配置文件中填写 client_id 和 client_secret；\
+                下载时使用保存的 token 通过 API 下载，要求 OAuth 应用被允许下载谱面，一般的第三方应用没有这个权限"
+    )]
+    oauth: bool,
     #[clap(short, long, help = "用户名", allow_hyphen_values = true)]
     user: Option<String>,
+    #[clap(
+        long,
+        help = "配置了多个账号时，优先使用最久没用过的账号，默认轮流使用"
+    )]
+    lru: bool,
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        help = "获取用户名和密码的顺序，用逗号隔开，可选 env、file、keyring、prompt，默认全部"
+    )]
+    credentials: Vec<String>,
+    #[clap(long, help = "代理地址，支持 http://、https:// 和 socks5://")]
+    proxy: Option<String>,
+    #[clap(long, help = "额外信任的根证书 (PEM 或 DER)，用于公司代理等情况")]
+    ca_cert: Option<PathBuf>,
+    #[clap(long, help = "连接超时时间，单位秒")]
+    connect_timeout: Option<u64>,
+    #[clap(long, help = "等待响应或下一段数据的超时时间，单位秒")]
+    read_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "删除系统密码管理器中保存的密码，可以用 -u 指定用户，默认删除所有账号的密码"
+    )]
+    forget: bool,
     #[clap(short, help = "清空缓存文件")]
     clear: bool,
     #[clap(short, long, help = "保存路径，默认当前目录")]
     save_path: Option<String>,
-    #[clap(short, help = "不下载包含视频的文件，默认不下载视频")]
+    #[clap(short, long, help = "下载包含视频的文件，默认不下载视频")]
     video: bool,
+    #[clap(
+        short,
+        long,
+        help = "使用镜像站下载，不需要登录。可选 nerinyan、catboy，或者包含 {sid} 的下载链接模板"
+    )]
+    mirror: Option<String>,
+    #[clap(long, default_value = "4", help = "同时发起的下载请求数量上限")]
+    max_requests: usize,
+    #[clap(long, default_value = "4", help = "同时写入磁盘的文件数量上限")]
+    max_writes: usize,
+    #[clap(
+        long,
+        default_value = "3",
+        help = "单个谱面遇到网络或服务器错误时最多尝试的次数"
+    )]
+    attempts: u32,
+    #[clap(long, help = "每个域名每分钟最多发出的请求数，默认不限制")]
+    rate_limit: Option<u32>,
+    #[clap(
+        long,
+        help = "所有下载加起来的速度上限，如 500K、2M，单位字节每秒，默认不限速"
+    )]
+    bandwidth: Option<String>,
+    #[clap(long, help = "单个谱面的下载速度上限，格式同 --bandwidth")]
+    bandwidth_per_download: Option<String>,
+    #[clap(
+        long,
+        help = "按 Ctrl-C 后等正在下载的谱面下载完再退出，默认立即中止并删除未完成的文件"
+    )]
+    finish_on_cancel: bool,
+    #[clap(long, help = "跳过保存路径中已经存在的谱面")]
+    skip_existing: bool,
+    #[clap(long, help = "osu! 的 Songs 目录，跳过其中已经有的谱面")]
+    songs: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "文件名模板，可用 {sid} {artist} {title} {creator} {novideo}，默认 {sid}.osz"
+    )]
+    name: Option<String>,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "pretty",
+        help = "日志格式，json 方便交给日志系统收集"
+    )]
+    log_format: LogFormat,
+    #[clap(
+        long,
+        parse(from_occurrences),
+        help = "输出更多日志，给两次包含每个请求，也可以用 RUST_LOG 环境变量设置"
+    )]
+    verbose: i8,
+    #[clap(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "只输出错误，-qq 不输出日志"
+    )]
+    quiet: i8,
+    #[clap(long, arg_enum, default_value = "zh", help = "错误信息的语言")]
+    lang: Lang,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum Lang {
+    Zh,
+    En,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum LogFormat {
+    Pretty,
+    Json,
 }
 
 /// Data for storing user's username, reusable cookie data and default download path.
@@ -37,24 +153,200 @@ struct Cli {
 struct Config {
     username: String,
     download_path: String,
+    /// osu! API v2 的 OAuth client，用于查询文件名模板需要的谱面信息
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    /// 其他账号，配置了多个账号时轮流使用它们下载，用 -l -u 添加
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    accounts: Vec<String>,
+    /// OAuth 登录的回调地址，默认 http://127.0.0.1:7270/callback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    redirect_uri: Option<String>,
+    /// 获取用户名和密码的顺序，命令行的 --credentials 优先
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    credentials: Vec<String>,
+    /// 保存 `用户名:密码` 的文件，默认是配置目录下的 credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credentials_file: Option<PathBuf>,
+    /// HTTP 客户端的设置，命令行参数优先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    /// 单位秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u64>,
+    /// 单位秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+    /// 替换 https://osu.ppy.sh，用于测试或者反向代理
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
+    /// 下载速度上限，如 "2M"，命令行参数优先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bandwidth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bandwidth_per_download: Option<String>,
+}
+
+impl Config {
+    /// The OAuth application for authorization code login, if the client is configured.
+    fn oauth_app(&self) -> Option<AuthorizationCode> {
+        let (id, secret) = (self.client_id.as_ref()?, self.client_secret.as_ref()?);
+        let redirect_uri = self
+            .redirect_uri
+            .as_deref()
+            .unwrap_or(oauth::DEFAULT_REDIRECT_URI);
+        Some(AuthorizationCode::new(id, secret, redirect_uri))
+    }
+
+    /// The HTTP client for every request, the command line wins over the config.
+    fn client(&self, cli: &Cli) -> Result<Client> {
+        let mut config = ClientConfig::new();
+        if let Some(proxy) = cli.proxy.as_ref().or(self.proxy.as_ref()) {
+            config = config.with_proxy(proxy);
+        }
+        if let Some(path) = cli.ca_cert.as_ref().or(self.ca_cert.as_ref()) {
+            config = config.with_ca_certificate(path);
+        }
+        if let Some(secs) = cli.connect_timeout.or(self.connect_timeout) {
+            config = config.with_connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = cli.read_timeout.or(self.read_timeout) {
+            config = config.with_read_timeout(Duration::from_secs(secs));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            config = config.with_user_agent(user_agent);
+        }
+        if let Some(base_url) = &self.base_url {
+            config = config.with_base_url(base_url);
+        }
+        Ok(config.build()?)
+    }
+
+    /// Where the username and password come from, `order` from the command line wins.
+    fn credentials(&self, order: &[String], config_path: &Path) -> Result<CredentialChain> {
+        let file = self
+            .credentials_file
+            .clone()
+            .unwrap_or_else(|| config_path.with_file_name("credentials"));
+        match (order, self.credentials.as_slice()) {
+            ([], []) => CredentialChain::new(credential::DEFAULT_ORDER, file),
+            ([], order) | (order, _) => CredentialChain::new(order, file),
+        }
+    }
 }
 
 async fn run(
     sid: Vec<String>,
-    user: &mut UserSession,
+    source: &dyn DownloadSource,
     path: &PathBuf,
-    no_video: bool,
+    options: &DownloadOptions,
 ) -> Result<()> {
     if !path.is_dir() {
         return Err(anyhow!("\"{:?}\"路径不存在", path));
     }
+    // installed only now, so Ctrl-C at a password prompt still quits the program
+    let cancel = cancel_on_ctrl_c();
+    let options = DownloadOptions {
+        cancel: Some(cancel.clone()),
+        ..options.clone()
+    };
     println!("正在下载...");
-    download(&sid, user, path.as_path(), no_video).await?;
+    let report = download_from(&sid, source, path.as_path(), &options).await?;
+
+    for entry in &report.entries {
+        println!("{entry}");
+    }
+    if cancel.is_cancelled() {
+        let not_started = report.not_started().count();
+        anyhow::bail!("下载已取消，{not_started} 个谱面没有开始");
+    }
+    let failed = report.failed().count();
+    if failed > 0 {
+        anyhow::bail!("{failed} 个谱面下载失败");
+    }
 
     println!("下载完成");
     Ok(())
 }
 
+/// Parse the `--mirror` argument, which is either a known mirror name or an url template.
+fn parse_mirror(mirror: &str) -> Result<MirrorSource> {
+    match mirror {
+        "nerinyan" => Ok(MirrorSource::nerinyan()),
+        "catboy" => Ok(MirrorSource::catboy()),
+        template if template.contains("{sid}") => Ok(MirrorSource::new("mirror", template)),
+        _ => Err(anyhow!("无法识别的镜像站：{mirror}")),
+    }
+}
+
+/// Cancel the downloads on the first Ctrl-C, exit at once on the second one.
+fn cancel_on_ctrl_c() -> CancelToken {
+    let token = CancelToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("正在取消下载，再按一次 Ctrl-C 立即退出");
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    token
+}
+
+/// Write the logs to stderr, `RUST_LOG` wins over `-v` and `-q`.
+fn init_logging(format: LogFormat, verbose: i8, quiet: i8) {
+    let level = match verbose - quiet {
+        i8::MIN..=-2 => "off",
+        -1 => "error",
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    // hyper and the other dependencies are too chatty below warn
+    let directives = match level {
+        "off" | "error" | "warn" => level.to_string(),
+        _ => format!("warn,osurs={level},osurs_map_download={level},download_cli={level}"),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(directives));
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Pretty => logger.init(),
+        LogFormat::Json => logger.json().init(),
+    }
+}
+
+/// Parse a rate such as `800`, `500K` or `2M` into bytes per second, K and M are 1024 based.
+/// A trailing `B` is allowed, a lowercase `b` means bits and is rejected to avoid confusion.
+fn parse_bandwidth(rate: &str) -> Result<u64> {
+    let rate = rate.trim().trim_end_matches("/s");
+    if rate.ends_with('b') {
+        anyhow::bail!("不支持以比特为单位的速度：{rate}，请使用字节，如 500K 或 500KB");
+    }
+    let rate = rate.trim_end_matches('B');
+    let (number, unit) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1024),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1024 * 1024),
+        _ => (rate, 1),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok((n * unit as f64) as u64),
+        _ => Err(anyhow!("无法识别的速度：{rate}，可以写成 800、500K 或 2M")),
+    }
+}
+
 /// Return configuration path for this application.
 /// If configuration file doesn't exist, it will try to create them.
 ///
@@ -97,7 +389,7 @@ fn save_config(cfg: &Config) -> Result<()> {
     Ok(())
 }
 
-// save recoverable data into cache directory
+// save recoverable data of each account into cache directory
 fn save_cookie(user: &UserSession) -> Result<()> {
     let basedir = BaseDirs::new().unwrap();
     let cache_dir = basedir.cache_dir();
@@ -107,21 +399,67 @@ fn save_cookie(user: &UserSession) -> Result<()> {
         fs::create_dir(&cache_dir).with_context(|| "创建缓存文件夹时出错")?;
     }
 
-    let cache_file = cache_dir.join("user-session");
+    let cache_file = cache_dir.join(session_file(user.username()));
     fs::write(cache_file, user.to_recoverable()).with_context(|| "写入用户缓存时出错")?;
 
     Ok(())
 }
 
-// get session from cache directory
-fn load_cookie() -> Option<String> {
+// cache file name of the account, characters that can't appear in a file name or would
+// leave the cache directory are percent-encoded, so different names never share a file
+fn session_file(username: &str) -> String {
+    let mut name = String::from("user-session-");
+    for b in username.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'[' | b']' | b' ' => {
+                name.push(b as char)
+            }
+            _ => name.push_str(&format!("%{b:02X}")),
+        }
+    }
+    name
+}
+
+// save OAuth token into cache directory, it is restored by `load_token`
+fn save_token(token: &OAuthToken) -> Result<()> {
+    let basedir = BaseDirs::new().unwrap();
+    let cache_dir = basedir.cache_dir().join("osu-map-downloader");
+    if !cache_dir.is_dir() {
+        fs::create_dir(&cache_dir).with_context(|| "创建缓存文件夹时出错")?;
+    }
+
+    let cache_file = cache_dir.join("oauth-token");
+    fs::write(cache_file, serde_json::to_string(token)?)
+        .with_context(|| "写入 token 缓存时出错")?;
+
+    Ok(())
+}
+
+fn load_token() -> Option<OAuthToken> {
+    let basedir = BaseDirs::new().unwrap();
+    let cache_file = basedir
+        .cache_dir()
+        .join("osu-map-downloader")
+        .join("oauth-token");
+    let data = fs::read(cache_file).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+// get session of the account from cache directory
+fn load_cookie(username: &str) -> Option<String> {
     let basedir = BaseDirs::new().unwrap();
     let cache_dir = basedir.cache_dir();
     let cache_dir = cache_dir.join("osu-map-downloader");
     if !cache_dir.is_dir() {
         return None;
     }
-    let cache_file = cache_dir.join("user-session");
+    let cache_file = cache_dir.join(session_file(username));
+    // older versions only saved one account to "user-session", hand it to the first account
+    // that is loaded, which is the one in `username` of the config
+    let legacy = cache_dir.join("user-session");
+    if !cache_file.is_file() && legacy.is_file() {
+        fs::rename(legacy, &cache_file).ok()?;
+    }
     fs::read_to_string(cache_file).ok()
 }
 
@@ -137,24 +475,87 @@ fn clean_cookie() -> Result<()> {
     Ok(fs::remove_dir_all(cache_dir)?)
 }
 
-async fn try_login(username: &String) -> Result<UserSession> {
-    let password = rpassword::prompt_password(format!("请输入 {username} 的密码: "))?;
-
-    UserSession::new(username, &password).await
+/// Restore the account from the cache, login again if there is no cookie or it expired.
+async fn restore_session(
+    username: &str,
+    creds: &CredentialChain,
+    client: &Client,
+) -> Result<UserSession> {
+    let data = match load_cookie(username) {
+        Some(data) => data,
+        None => return try_login(username, creds, client).await,
+    };
+    let mut session = UserSession::restore(username, &data)
+        .with_context(|| format!("无法恢复 {username} 的登录状态，请使用 -c 参数清理重试"))?
+        .with_client(client.clone());
+    // with a known password the session can login again by itself while downloading
+    let password = creds
+        .passwords(username, false)
+        .find_map(|password| match password {
+            Ok((password, _)) => Some(password),
+            Err(e) => {
+                warn!("{e:#}");
+                None
+            }
+        });
+    if let Some(password) = password {
+        session.set_password(password);
+    }
+    // only ask for the password when the cached cookie really expired
+    match session.check().await {
+        SessionState::Valid => Ok(session),
+        SessionState::Expired => {
+            println!("{username} 的登录已过期，正在重新登录");
+            try_login(username, creds, client).await
+        }
+        SessionState::Unknown => {
+            println!("无法确认 {username} 的登录状态，继续使用缓存的 cookie");
+            Ok(session)
+        }
+    }
 }
 
-fn prompt_up_for_username() -> String {
-    println!("没有用户名，请输入你的 osu 用户名: ");
-    let mut buffer = String::new();
-    std::io::stdin()
-        .read_line(&mut buffer)
-        .expect("非法的用户名输入，请重试");
-    buffer.trim().to_string()
+/// Try the passwords from the providers in order, a typed password that works is saved.
+async fn try_login(
+    username: &str,
+    creds: &CredentialChain,
+    client: &Client,
+) -> Result<UserSession> {
+    let mut last_error = None;
+    for password in creds.passwords(username, true) {
+        // a broken provider (e.g. a locked keyring) shouldn't stop the others
+        let (password, typed) = match password {
+            Ok(password) => password,
+            Err(e) => {
+                warn!("{e:#}");
+                last_error = Some(e);
+                continue;
+            }
+        };
+        match UserSession::new_with_client(client.clone(), username, &password).await {
+            Ok(user) => {
+                if typed {
+                    creds.store(username, &password);
+                }
+                return Ok(user);
+            }
+            Err(e) => {
+                println!("{username} 登录失败：{e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("找不到 {username} 的密码")))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
+    init_logging(cli.log_format, cli.verbose, cli.quiet);
+    set_language(match cli.lang {
+        Lang::Zh => Language::Chinese,
+        Lang::En => Language::English,
+    });
 
     let config_path = find_or_new_cfg_path()?;
 
@@ -173,9 +574,57 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    let mut config = read_config(&config_path).unwrap_or_default();
+    let creds = config.credentials(&cli.credentials, &config_path)?;
+    let client = config.client(&cli)?;
+
+    if cli.login && cli.oauth {
+        let app = config
+            .oauth_app()
+            .ok_or_else(|| anyhow!("请先在配置文件中填写 client_id 和 client_secret"))?
+            .with_client(client.clone());
+        let session = oauth::login(app).await?;
+        save_token(&session.token().await)?;
+        println!("登录成功");
+        return Ok(());
+    }
+
+    if cli.forget {
+        if !cfg!(feature = "pswd-store") {
+            anyhow::bail!("没有启用 pswd-store 功能，不会保存任何密码");
+        }
+        let keyring = config.credentials(&["keyring".to_string()], &config_path)?;
+        let users = match cli.user {
+            Some(user) => vec![user],
+            None => std::iter::once(config.username)
+                .chain(config.accounts)
+                .filter(|name| !name.is_empty())
+                .collect(),
+        };
+        for user in &users {
+            keyring.forget(user)?;
+            println!("已删除 {user} 保存的密码");
+        }
+        return Ok(());
+    }
+
     if cli.login {
-        let user = try_login(&cli.user.unwrap_or_else(prompt_up_for_username)).await?;
+        let name = match cli.user {
+            Some(name) => name,
+            None => creds.username()?,
+        };
+        let user = try_login(&name, &creds, &client).await?;
         save_cookie(&user)?;
+
+        // remember the account, the first one becomes the default user
+        let name = user.username().to_string();
+        if config.username.is_empty() {
+            config.username = name;
+            save_config(&config)?;
+        } else if config.username != name && !config.accounts.contains(&name) {
+            config.accounts.push(name);
+            save_config(&config)?;
+        }
         return Ok(());
     }
 
@@ -183,7 +632,6 @@ async fn main() -> Result<()> {
         anyhow::bail!("请指定谱面 sid，使用 -h 选项来获取更多信息")
     }
 
-    let mut config = read_config(&config_path).unwrap_or_default();
     let mut is_cfg_updated = false;
 
     if let Some(path) = cli.save_path {
@@ -191,8 +639,71 @@ async fn main() -> Result<()> {
         is_cfg_updated = true;
     }
 
+    let download_path = PathBuf::from(&config.download_path);
+    set_rate_limit(cli.rate_limit.map(RateLimit::per_minute));
+    let mut options = DownloadOptions {
+        no_video: !cli.video,
+        max_requests: cli.max_requests,
+        max_writes: cli.max_writes,
+        retry: RetryPolicy {
+            max_attempts: cli.attempts.max(1),
+            ..Default::default()
+        },
+        progress: Arc::new(BarProgress::default()),
+        skip_existing: cli.skip_existing,
+        songs_dir: cli.songs,
+        file_name: cli.name.map(FileNameTemplate::new).unwrap_or_default(),
+        client: Some(client.clone()),
+        cancel_policy: if cli.finish_on_cancel {
+            CancelPolicy::Finish
+        } else {
+            CancelPolicy::Abort
+        },
+        bandwidth: cli
+            .bandwidth
+            .or(config.bandwidth.clone())
+            .map(|rate| parse_bandwidth(&rate))
+            .transpose()?
+            .map(Bandwidth::new),
+        bandwidth_per_download: cli
+            .bandwidth_per_download
+            .or(config.bandwidth_per_download.clone())
+            .map(|rate| parse_bandwidth(&rate))
+            .transpose()?,
+        ..Default::default()
+    };
+    if let (Some(id), Some(secret)) = (&config.client_id, &config.client_secret) {
+        let provider = ClientCredentials::new(id, secret).with_client(client.clone());
+        options.metadata = Some(Arc::new(provider));
+    }
+    // mirror doesn't need any account, skip the login process
+    if let Some(mirror) = cli.mirror {
+        if is_cfg_updated {
+            save_config(&config)?;
+        }
+        let source = parse_mirror(&mirror)?;
+        return run(cli.sid, &source, &download_path, &options).await;
+    }
+
+    // the token saved by `-l --oauth` is only used when asked for, most OAuth apps are not
+    // allowed to download
+    if cli.oauth {
+        let app = config
+            .oauth_app()
+            .ok_or_else(|| anyhow!("请先在配置文件中填写 client_id 和 client_secret"))?;
+        let token = load_token()
+            .ok_or_else(|| anyhow!("没有保存的 OAuth token，请先使用 -l --oauth 登录"))?;
+        if is_cfg_updated {
+            save_config(&config)?;
+        }
+        let source = OAuthSession::from_token(app.with_client(client.clone()), token);
+        let res = run(cli.sid, &source, &download_path, &options).await;
+        save_token(&source.token().await)?;
+        return res;
+    }
+
     if config.username.is_empty() {
-        config.username = prompt_up_for_username();
+        config.username = creds.username()?;
         is_cfg_updated = true;
     }
 
@@ -200,18 +711,52 @@ async fn main() -> Result<()> {
         save_config(&config)?;
     }
 
-    let recover_data = load_cookie();
-    let download_path = PathBuf::from(config.download_path);
-    // if no previous session, handle login
-    let mut session = if let Some(data) = recover_data {
-        UserSession::from_recoverable(&config.username, &data)
-            .ok_or_else(|| anyhow::anyhow!("非法的 session 数据，请使用 -c 参数清理重试"))?
+    if config.accounts.is_empty() {
+        let source = OfficialSource::new(restore_session(&config.username, &creds, &client).await?);
+        let res = run(cli.sid, &source, &download_path, &options).await;
+        save_cookie(&source.into_session())?;
+        return res;
+    }
+
+    // several accounts, spread the downloads across them
+    let mut sessions = vec![];
+    for name in std::iter::once(&config.username).chain(&config.accounts) {
+        sessions.push(restore_session(name, &creds, &client).await?);
+    }
+    let strategy = if cli.lru {
+        PoolStrategy::LeastRecentlyUsed
     } else {
-        try_login(&config.username).await?
+        PoolStrategy::RoundRobin
     };
-
-    run(cli.sid, &mut session, &download_path, cli.video).await?;
-    save_cookie(&session)?;
+    let source = SessionPool::new(sessions).with_strategy(strategy);
+    let res = run(cli.sid, &source, &download_path, &options).await;
+    for session in source.into_sessions() {
+        save_cookie(&session)?;
+    }
+    res?;
 
     Ok(())
 }
+
+#[test]
+fn test_parse_bandwidth() {
+    assert_eq!(parse_bandwidth("800").unwrap(), 800);
+    assert_eq!(parse_bandwidth("500K").unwrap(), 500 * 1024);
+    assert_eq!(parse_bandwidth("500KB/s").unwrap(), 500 * 1024);
+    assert_eq!(parse_bandwidth("2M").unwrap(), 2 * 1024 * 1024);
+    assert_eq!(parse_bandwidth("1.5M").unwrap(), 1536 * 1024);
+    assert!(parse_bandwidth("0").is_err());
+    assert!(parse_bandwidth("500Kb").is_err());
+    assert!(parse_bandwidth("fast").is_err());
+    assert!(parse_bandwidth("").is_err());
+}
+
+#[test]
+fn test_session_file() {
+    assert_eq!(session_file("peppy"), "user-session-peppy");
+    assert_eq!(session_file("-[Foo]_"), "user-session--[Foo]_");
+    assert_eq!(session_file("a b"), "user-session-a b");
+    assert_eq!(session_file("../x"), "user-session-%2E%2E%2Fx");
+    assert_eq!(session_file("c:\\"), "user-session-c%3A%5C");
+    assert_ne!(session_file("a/b"), session_file("a_b"));
+}
@@ -0,0 +1,171 @@
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use osurs::map_download::prelude::*;
+
+/// 默认的回调地址，需要和 OAuth 应用里填写的 Application Callback URL 一致
+pub const DEFAULT_REDIRECT_URI: &str = "http://127.0.0.1:7270/callback";
+
+/// 等待用户在浏览器中授权的最长时间
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 单个连接发送请求的最长时间，浏览器预连接的 socket 可能一直不发送内容
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DONE_PAGE: &str = "<html><body>登录成功，可以关闭这个页面了。</body></html>";
+const FAIL_PAGE: &str = "<html><body>登录失败，请回到命令行查看原因。</body></html>";
+
+/// Open the authorize page in a browser, wait for the redirect on a local port and exchange
+/// the code for tokens.
+pub async fn login(app: AuthorizationCode) -> Result<OAuthSession> {
+    let redirect = Url::parse(app.redirect_uri()).with_context(|| "非法的回调地址")?;
+    let host = redirect.host_str().unwrap_or("127.0.0.1");
+    let port = redirect.port_or_known_default().unwrap_or(80);
+    let listener = TcpListener::bind((host, port))
+        .await
+        .with_context(|| format!("无法监听回调地址 {host}:{port}"))?;
+
+    let state: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let url = app.authorize_url(&state)?;
+    println!("请在浏览器中打开以下链接并授权：\n{url}");
+    open_browser(&url);
+
+    let code = tokio::time::timeout(LOGIN_TIMEOUT, wait_for_code(&listener, &state))
+        .await
+        .map_err(|_| anyhow!("等待授权超时"))??;
+    app.exchange(&code).await
+}
+
+/// Accept connections until one of them carries the authorization code. Every connection is
+/// read in its own task, so an idle or broken one doesn't hold up the others.
+async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => continue,
+                };
+                let (tx, state) = (tx.clone(), state.to_string());
+                tokio::spawn(async move {
+                    if let Ok(Some(result)) =
+                        tokio::time::timeout(READ_TIMEOUT, handle_callback(stream, &state)).await
+                    {
+                        let _ = tx.send(result).await;
+                    }
+                });
+            }
+            Some(result) = rx.recv() => return result,
+        }
+    }
+}
+
+/// Answer one connection, return `None` if it is not the redirect we are waiting for.
+async fn handle_callback(mut stream: TcpStream, state: &str) -> Option<Result<String>> {
+    let mut buf = vec![0; 8192];
+    let n = stream.read(&mut buf).await.ok()?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+
+    // GET /callback?code=...&state=... HTTP/1.1
+    let target = request.lines().next().and_then(|l| l.split(' ').nth(1))?;
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(target)) {
+        Ok(url) => url,
+        Err(_) => {
+            respond(&mut stream, "400 Bad Request", "").await;
+            return None;
+        }
+    };
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.to_string())
+    };
+    // browsers may ask for favicon.ico and so on
+    if query("code").is_none() && query("error").is_none() {
+        respond(&mut stream, "404 Not Found", "").await;
+        return None;
+    }
+
+    // not the redirect we are waiting for, keep waiting
+    if query("state").as_deref() != Some(state) {
+        respond(&mut stream, "400 Bad Request", "").await;
+        return None;
+    }
+
+    let result = match (query("code"), query("error")) {
+        (Some(code), _) => Ok(code),
+        (None, error) => Err(anyhow!("授权失败: {}", error.unwrap_or_default())),
+    };
+    let page = if result.is_ok() { DONE_PAGE } else { FAIL_PAGE };
+    respond(&mut stream, "200 OK", page).await;
+    Some(result)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    // the browser closing the page early is not our problem
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Try to open the url with the system browser, the url is already printed if this fails.
+fn open_browser(url: &str) {
+    let result = if cfg!(target_os = "windows") {
+        // `cmd /C start` would treat the `&` in the query as a command separator
+        Command::new("rundll32")
+            .args(["url.dll,FileProtocolHandler", url])
+            .spawn()
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg(url).spawn()
+    } else {
+        Command::new("xdg-open").arg(url).spawn()
+    };
+    if result.is_err() {
+        println!("无法自动打开浏览器，请手动复制链接");
+    }
+}
+
+#[tokio::test]
+async fn test_wait_for_code() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let get = |path: &'static str| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    let browser = async {
+        // a preconnected socket that never sends anything
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        assert!(get("/favicon.ico").await.starts_with("HTTP/1.1 404"));
+        assert!(get("http://[::1").await.starts_with("HTTP/1.1 400"));
+        assert!(get("/callback?code=stolen&state=other")
+            .await
+            .starts_with("HTTP/1.1 400"));
+        assert!(get("/callback?code=abc&state=xyz")
+            .await
+            .starts_with("HTTP/1.1 200"));
+    };
+    let (code, _) = tokio::join!(wait_for_code(&listener, "xyz"), browser);
+    assert_eq!(code.unwrap(), "abc");
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use osurs::map_download::prelude::*;
+
+/// 使用 indicatif 为每个正在下载的谱面显示一个进度条
+#[derive(Debug, Default)]
+pub struct BarProgress {
+    multi: MultiProgress,
+    bars: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl BarProgress {
+    fn style() -> ProgressStyle {
+        ProgressStyle::default_bar()
+            .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .expect("非法的进度条模板")
+            .progress_chars("#>-")
+    }
+}
+
+impl ProgressObserver for BarProgress {
+    fn started(&self, sid: &str, total: u64, offset: u64) {
+        let mut bars = self.bars.lock().unwrap();
+        let bar = bars.entry(sid.to_string()).or_insert_with(|| {
+            let bar = self.multi.add(ProgressBar::new(total));
+            bar.set_style(Self::style());
+            bar
+        });
+        bar.set_length(total);
+        bar.set_position(offset);
+        if offset > 0 {
+            bar.set_message(format!("正在续传谱面 {sid}"));
+        } else {
+            bar.set_message(format!("正在下载谱面 {sid}"));
+        }
+    }
+
+    fn advanced(&self, sid: &str, bytes: u64) {
+        if let Some(bar) = self.bars.lock().unwrap().get(sid) {
+            bar.inc(bytes);
+        }
+    }
+
+    fn finished(&self, sid: &str, path: &Path) {
+        if let Some(bar) = self.bars.lock().unwrap().remove(sid) {
+            bar.finish_with_message(format!("谱面下载完成，保存到: {}", path.display()));
+        }
+    }
+
+    fn failed(&self, sid: &str, error: &DownloadError) {
+        if let Some(bar) = self.bars.lock().unwrap().remove(sid) {
+            bar.abandon_with_message(format!("谱面 {sid} 下载失败: {error:#}"));
+        }
+    }
+}
@@ -1,9 +1,19 @@
 use anyhow::{anyhow, Result};
 use keyring::{Entry, Error};
 
+/// Service name of the passwords in the keyring manager.
+const SERVICE: &str = "osu-map-downloader";
+
+/// Keyring entry of the given user.
+pub fn entry(username: &str) -> Entry {
+    Entry::new(SERVICE, username)
+}
+
 /// Set password into keyring manager.
 pub fn set(entry: &Entry, username: &str, pswd: &str) -> Result<()> {
-    Ok(entry.set_password(pswd)?)
+    entry
+        .set_password(pswd)
+        .map_err(|err| anyhow!("fail to save password for user: {username}: {err}"))
 }
 
 /// Get password from keyring manager.
@@ -14,3 +24,13 @@ pub fn get(entry: &Entry, username: &str) -> Result<String> {
         Err(err) => Err(anyhow!("fail to get password from user: {username}: {err}")),
     }
 }
+
+/// Remove password from keyring manager, it's fine if there is no password.
+pub fn delete(entry: &Entry, username: &str) -> Result<()> {
+    match entry.delete_password() {
+        Ok(()) | Err(Error::NoEntry) => Ok(()),
+        Err(err) => Err(anyhow!(
+            "fail to delete password of user: {username}: {err}"
+        )),
+    }
+}